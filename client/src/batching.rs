@@ -1,90 +1,396 @@
 #![allow(unused, dead_code)]
-use crate::collision::Box3D;
-use crate::helper::{self, create_texture};
+use crate::broadphase::{AabbTree, ProxyId};
+use crate::collision::{Box3D, Collider, ConvexHull, Frustum, Obb3D, Ray};
+use crate::helper::{self, create_batch_texture};
+use crate::mesh::MeshData;
+use crate::optimize::{
+    ACMR_CACHE_SIZE, CacheReport, acmr, optimize_vertex_cache, optimize_vertex_fetch,
+};
+use crate::simplify::append_lods;
+use crate::transform::{transform_vertices, translate_positions};
+use crate::vertex::{Semantic, VertexLayout};
 use gl33::*;
 use std::any::type_name;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::ffi::c_void;
+use std::ops::Range;
 use std::rc::Rc;
-use tobj;
-use ultraviolet::{Mat4, Vec3, Vec4};
-
-const VERTEX_LEN: usize = size_of::<[f32; 8]>();
+use ultraviolet::{Mat3, Mat4, Rotor3, Vec3, Vec4};
 
 #[derive(Debug, Clone)]
 pub struct BatchObject {
     pub vertex_data: Vec<f32>,
     pub indices: Vec<u32>,
-    pub vertex_len: usize,
+    pub layout: VertexLayout,
     pub position: Vec3,
     pub hitbox: Box3D,
+    pub parts: Vec<MeshPart>,
+    // index ranges of levels of detail, lods[0] is the full mesh
+    pub lods: Vec<Range<usize>>,
+}
+
+// One model (o/g group) of the OBJ file, offsets are in vertices and indices of the merged
+// BatchObject (or MeshData)
+#[derive(Debug, Clone)]
+pub struct MeshPart {
+    pub name: String,
+    pub vertex_offset: usize,
+    pub vertex_count: usize,
+    pub index_offset: usize,
+    pub index_count: usize,
+    pub hitbox: Box3D,
 }
 
 impl BatchObject {
+    // Parses the OBJ file every call, use MeshCache::batch_object for meshes placed many times
     pub fn new(gl: Rc<GlFns>, obj_path: &str, position: Vec3) -> Self {
-        let (models, _) = tobj::load_obj(
-            obj_path,
-            &tobj::LoadOptions {
-                triangulate: true,
-                single_index: true,
-                ..Default::default()
-            },
-        )
-        .expect("Failed to load OBJ");
+        Self::from_mesh(&MeshData::load(obj_path), position)
+    }
 
-        let mesh = &models[0].mesh;
+    // Copy of the parsed mesh moved to position
+    pub fn from_mesh(mesh: &MeshData, position: Vec3) -> Self {
+        let mut vertex_data = mesh.vertex_data.clone();
+        translate_positions(&mesh.layout, &mut vertex_data, position);
 
-        let positions = &mesh.positions;
-        let normals = &mesh.normals;
-        let texcoords = &mesh.texcoords;
-        let indices = mesh.indices.clone();
-        let mut vertex_data: Vec<f32> = vec![];
-        let mut min_vertex: Vec3 = Vec3::new(f32::MAX, f32::MAX, f32::MAX);
-        let mut max_vertex: Vec3 = Vec3::new(f32::MIN, f32::MIN, f32::MIN);
-        for i in 0..(positions.len() / 3) {
-            let transform = Mat4::from_translation(position);
-
-            let vert = Vec3::from(
-                transform
-                    * Vec4::new(
-                        positions[3 * i],
-                        positions[3 * i + 1],
-                        positions[3 * i + 2],
-                        1.0,
-                    ),
-            );
+        let mut hitbox = mesh.hitbox;
+        hitbox.move_delta(position);
+        let parts = mesh
+            .parts
+            .iter()
+            .map(|part| {
+                let mut part = part.clone();
+                part.hitbox.move_delta(position);
+                part
+            })
+            .collect();
 
-            min_vertex.x = min_vertex.x.min(vert.x);
-            min_vertex.y = min_vertex.y.min(vert.y);
-            min_vertex.z = min_vertex.z.min(vert.z);
+        Self {
+            vertex_data,
+            lods: mesh.lods.clone(),
+            indices: mesh.indices.clone(),
+            layout: mesh.layout.clone(),
+            position,
+            hitbox,
+            parts,
+        }
+    }
 
-            max_vertex.x = max_vertex.x.max(vert.x);
-            max_vertex.y = max_vertex.y.max(vert.y);
-            max_vertex.z = max_vertex.z.max(vert.z);
+    // Coarser levels of detail generated from the full mesh, ratio is the part of triangles kept
+    pub fn generate_lods(&mut self, ratios: &[f32]) {
+        append_lods(
+            &self.layout,
+            &self.vertex_data,
+            &mut self.indices,
+            &mut self.lods,
+            ratios,
+        );
+    }
 
-            let norm = Vec3::from(
-                transform.inversed().transposed()
-                    * Vec4::new(normals[3 * i], normals[3 * i + 1], normals[3 * i + 2], 1.0),
-            );
+    // Hull around every vertex, in world space like the vertices
+    pub fn convex_hull(&self) -> ConvexHull {
+        ConvexHull::from_vertex_data(&self.layout, &self.vertex_data)
+    }
 
-            vertex_data.push(vert.x);
-            vertex_data.push(vert.y);
-            vertex_data.push(vert.z);
+    // Splits merged object back into one BatchObject per part, indices are rebased to start from
+    // zero so every part can be consumed on its own
+    pub fn split_parts(self) -> Vec<(String, BatchObject)> {
+        self.parts
+            .iter()
+            .map(|part| {
+                let start = part.vertex_offset * self.layout.floats();
+                let end = (part.vertex_offset + part.vertex_count) * self.layout.floats();
+                let offset = part.vertex_offset as u32;
+                let indices = self.indices[part.index_offset..part.index_offset + part.index_count]
+                    .iter()
+                    .map(|index| index - offset)
+                    .collect();
 
-            vertex_data.push(norm.x);
-            vertex_data.push(norm.y);
-            vertex_data.push(norm.z);
+                (
+                    part.name.clone(),
+                    BatchObject {
+                        vertex_data: self.vertex_data[start..end].to_vec(),
+                        indices,
+                        layout: self.layout.clone(),
+                        position: self.position,
+                        hitbox: part.hitbox,
+                        lods: vec![Range {
+                            start: 0,
+                            end: part.index_count,
+                        }],
+                        parts: vec![MeshPart {
+                            name: part.name.clone(),
+                            vertex_offset: 0,
+                            vertex_count: part.vertex_count,
+                            index_offset: 0,
+                            index_count: part.index_count,
+                            hitbox: part.hitbox,
+                        }],
+                    },
+                )
+            })
+            .collect()
+    }
+}
 
-            vertex_data.push(texcoords[2 * i]);
-            vertex_data.push(texcoords[2 * i + 1]);
+// Every BatchObject of one batch has to share the layout of the first one, which has to fit
+// the shader
+fn check_layout(batch_layout: &mut Option<VertexLayout>, layout: &VertexLayout) {
+    match batch_layout {
+        Some(batch_layout) if batch_layout != layout => {
+            panic!("BatchObject layout differs from layout of the batch!");
+        }
+        Some(_) => {}
+        None => {
+            if let Err(error) = layout.check_shader_inputs() {
+                panic!("BatchObject layout does not fit the shader: {}!", error);
+            }
+            *batch_layout = Some(layout.clone());
+        }
+    }
+}
+
+// Distances where coarser levels of detail start, lods[i + 1] is used beyond thresholds[i]
+// With hysteresis the level changes only hysteresis past the threshold, so objects standing at the
+// threshold don't switch every frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct LodSettings {
+    pub thresholds: Vec<f32>,
+    pub hysteresis: f32,
+}
+
+impl LodSettings {
+    pub fn new(thresholds: Vec<f32>) -> Self {
+        Self {
+            thresholds,
+            hysteresis: 0.0,
+        }
+    }
+
+    pub fn with_hysteresis(mut self, hysteresis: f32) -> Self {
+        self.hysteresis = hysteresis;
+        self
+    }
+
+    // Walks from the current level, so hysteresis only works against the last choice
+    // current is clamped first, it can be past the thresholds when settings with fewer of them
+    // came in
+    pub fn select(&self, current: usize, distance: f32, lod_count: usize) -> usize {
+        let mut lod = current
+            .min(lod_count.saturating_sub(1))
+            .min(self.thresholds.len());
+        while lod + 1 < lod_count
+            && lod < self.thresholds.len()
+            && distance > self.thresholds[lod] + self.hysteresis
+        {
+            lod += 1;
+        }
+        while lod > 0 && distance < self.thresholds[lod - 1] - self.hysteresis {
+            lod -= 1;
+        }
+        lod
+    }
+}
+
+// None draws every object with its full mesh, new settings start from it too (until the next
+// update_lods), levels picked by the old ones can be past the new thresholds
+fn apply_lod_settings<'a>(
+    batch_settings: &mut Option<LodSettings>,
+    lod_settings: Option<LodSettings>,
+    object_lods: impl Iterator<Item = &'a mut usize>,
+) {
+    object_lods.for_each(|lod| *lod = 0);
+    *batch_settings = lod_settings;
+}
+
+// Picks level of detail of every object (hitbox, level count, current level) by distance of its
+// hitbox center from the camera, true when any level changed
+// Batches call it from update_lods, which is meant to be called every frame before draw
+fn select_lods<'a>(
+    lod_settings: &Option<LodSettings>,
+    objects: impl Iterator<Item = (&'a Box3D, usize, &'a mut usize)>,
+    camera_position: Vec3,
+) -> bool {
+    let lod_settings = match lod_settings {
+        Some(lod_settings) => lod_settings,
+        None => return false,
+    };
+    let mut changed = false;
+    for (hitbox, lod_count, lod) in objects {
+        let distance = (hitbox.center() - camera_position).mag();
+        let selected = lod_settings.select(*lod, distance, lod_count);
+        if selected != *lod {
+            *lod = selected;
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Glues index ranges sorted by start together where one ends at the start of the next one and
+// both have the same base vertex
+fn merge_ranges(ranges: impl Iterator<Item = (Range<usize>, usize)>) -> Vec<(Range<usize>, usize)> {
+    let mut merged: Vec<(Range<usize>, usize)> = vec![];
+    for (range, base_vertex) in ranges.filter(|(range, _)| !range.is_empty()) {
+        match merged.last_mut() {
+            Some((last, last_base)) if last.end == range.start && *last_base == base_vertex => {
+                last.end = range.end
+            }
+            _ => merged.push((range, base_vertex)),
+        }
+    }
+    merged
+}
+
+// What drawing needs of an object of StaticBatch or DynamicBatch
+struct DrawnObject<'a> {
+    index_offset: usize,
+    // index range of the picked level of detail, relative to index_offset
+    lod: &'a Range<usize>,
+    base_vertex: usize,
+    hitbox: &'a Box3D,
+    visible: bool,
+}
+
+impl DrawnObject<'_> {
+    fn draw_range(&self) -> Range<usize> {
+        self.index_offset + self.lod.start..self.index_offset + self.lod.end
+    }
+}
+
+// Merged index ranges (with base vertex) of visible objects. With a frustum, visible objects with
+// hitbox outside of it are left out too and counted, the count is what culled() of the batch
+// reports after the draw (plain draw leaves out none)
+fn build_draw_ranges<'a>(
+    objects: impl Iterator<Item = DrawnObject<'a>>,
+    frustum: Option<&Frustum>,
+) -> (Vec<(Range<usize>, usize)>, usize) {
+    let mut culled = 0;
+    let mut ranges: Vec<(Range<usize>, usize)> = objects
+        .filter(|object| object.visible)
+        .filter(|object| {
+            let inside = frustum.is_none_or(|frustum| frustum.intersects(object.hitbox));
+            if !inside {
+                culled += 1;
+            }
+            inside
+        })
+        .map(|object| (object.draw_range(), object.base_vertex))
+        .collect();
+    ranges.sort_by_key(|(range, _)| range.start);
+    (merge_ranges(ranges.into_iter()), culled)
+}
+
+// vertices one u16 index can address from its base vertex
+const U16_VERTICES: usize = u16::MAX as usize + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexType {
+    U16,
+    U32,
+}
+
+impl IndexType {
+    pub fn size(&self) -> usize {
+        match self {
+            IndexType::U16 => size_of::<u16>(),
+            IndexType::U32 => size_of::<u32>(),
+        }
+    }
+
+    pub fn gl_type(&self) -> GLenum {
+        match self {
+            IndexType::U16 => GL_UNSIGNED_SHORT,
+            IndexType::U32 => GL_UNSIGNED_INT,
+        }
+    }
+}
+
+// Indices relative to base_vertex in the bytes of the index type, u32 ones from vertex 0 are
+// borrowed as they are
+fn encode_indices(indices: &[u32], base_vertex: usize, index_type: IndexType) -> Cow<'_, [u8]> {
+    let base_vertex = base_vertex as u32;
+    match index_type {
+        IndexType::U32 if base_vertex == 0 => Cow::Borrowed(bytemuck::cast_slice(indices)),
+        IndexType::U16 => Cow::Owned(
+            indices
+                .iter()
+                .flat_map(|index| ((index - base_vertex) as u16).to_ne_bytes())
+                .collect(),
+        ),
+        IndexType::U32 => Cow::Owned(
+            indices
+                .iter()
+                .flat_map(|index| (index - base_vertex).to_ne_bytes())
+                .collect(),
+        ),
+    }
+}
+
+// Splits vertices of a batch into runs u16 indices can address, every run is drawn with its own
+// base vertex. Batch goes to u32 (one run from vertex 0) only when one object is too big for u16
+struct IndexSegments {
+    index_type: IndexType,
+    bases: Vec<usize>,
+}
+
+impl IndexSegments {
+    fn new() -> Self {
+        Self {
+            index_type: IndexType::U16,
+            bases: vec![0],
+        }
+    }
+
+    // Segment of the object appended at vertex_offset, second value is true when the batch has
+    // just switched to u32 and uploaded indices are no longer valid
+    fn add(&mut self, vertex_offset: usize, vertex_count: usize) -> (usize, bool) {
+        if self.index_type == IndexType::U32 {
+            return (0, false);
+        }
+        if vertex_count > U16_VERTICES {
+            self.index_type = IndexType::U32;
+            return (0, true);
+        }
+        if vertex_offset + vertex_count - self.bases.last().unwrap() > U16_VERTICES {
+            self.bases.push(vertex_offset);
+        }
+        (self.bases.len() - 1, false)
+    }
+
+    fn base_vertex(&self, segment: usize) -> usize {
+        match self.index_type {
+            IndexType::U16 => self.bases[segment],
+            IndexType::U32 => 0,
+        }
+    }
+}
+
+// Index of an object in StaticBatch, returned by consume_object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StaticObjectId(usize);
+
+struct StaticObject {
+    vertex_offset: usize,
+    vertex_count: usize,
+    index_offset: usize,
+    index_count: usize,
+    segment: usize,
+    lods: Vec<Range<usize>>,
+    lod: usize,
+    hitbox: Box3D,
+    visible: bool,
+}
+
+impl StaticObject {
+    fn drawn<'a>(&'a self, segments: &IndexSegments) -> DrawnObject<'a> {
+        DrawnObject {
+            index_offset: self.index_offset,
+            lod: &self.lods[self.lod],
+            base_vertex: segments.base_vertex(self.segment),
+            hitbox: &self.hitbox,
+            visible: self.visible,
         }
-        return Self {
-            vertex_data: vertex_data,
-            indices: indices,
-            vertex_len: 8,
-            position: position,
-            hitbox: Box3D::new(max_vertex, min_vertex),
-        };
     }
 }
 
@@ -92,9 +398,19 @@ pub struct StaticBatch {
     vertex_data: Option<Vec<f32>>,
     indices: Option<Vec<u32>>,
     offset: usize,
+    layout: Option<VertexLayout>,
+    objects: Vec<StaticObject>,
+    segments: IndexSegments,
+    // merged index ranges (with base vertex) of visible objects, None after visibility changed
+    draw_ranges: Option<Vec<(Range<usize>, usize)>>,
+    culled: usize,
+    lod_settings: Option<LodSettings>,
+    // false drops vertex_data and indices once they are on the GPU
+    keep_cpu_data: bool,
     ebo: Option<helper::Buffer>,
     vbo: Option<helper::Buffer>,
     vao: Option<helper::VertexArray>,
+    texture: Option<u32>,
 }
 
 // no hitboxes
@@ -104,46 +420,144 @@ impl StaticBatch {
             vertex_data: None,
             indices: None,
             offset: 0,
+            layout: None,
+            objects: vec![],
+            segments: IndexSegments::new(),
+            draw_ranges: None,
+            culled: 0,
+            lod_settings: None,
+            keep_cpu_data: true,
             vbo: None,
             ebo: None,
             vao: None,
+            texture: None,
         }
     }
 
-    pub fn consume_object(&mut self, mut object: BatchObject) {
+    // Without CPU copy the batch takes no more objects and can not be sent again after release
+    pub fn set_keep_cpu_data(&mut self, keep_cpu_data: bool) {
+        self.keep_cpu_data = keep_cpu_data;
+    }
+
+    // false once the CPU copy was dropped after send_data
+    pub fn has_cpu_data(&self) -> bool {
+        self.objects.is_empty() || self.vertex_data.is_some()
+    }
+
+    pub fn consume_object(&mut self, mut object: BatchObject) -> StaticObjectId {
+        if !self.has_cpu_data() {
+            panic!("StaticBatch dropped its CPU data, it can not take more objects!");
+        }
+        check_layout(&mut self.layout, &object.layout);
+        let index_offset = self.indices.as_ref().map_or(0, |indices| indices.len());
+        let vertex_count = object.vertex_data.len() / object.layout.floats();
+        // buffers are made by send_data, so switching to u32 needs nothing else
+        let (segment, _) = self.segments.add(self.offset, vertex_count);
+        self.objects.push(StaticObject {
+            vertex_offset: self.offset,
+            vertex_count,
+            index_offset,
+            index_count: object.indices.len(),
+            segment,
+            lods: object.lods.clone(),
+            lod: 0,
+            hitbox: object.hitbox,
+            visible: true,
+        });
+        self.draw_ranges = None;
         match (&mut self.vertex_data, &mut self.indices) {
             (Some(vertex_data), Some(indices_data)) => {
                 let offset = self.offset as u32;
                 let mut new_indices_data = object.indices;
                 new_indices_data.iter_mut().for_each(|elem| *elem += offset);
-                self.offset += object.vertex_data.len() / object.vertex_len;
+                self.offset += object.vertex_data.len() / object.layout.floats();
 
                 vertex_data.append(&mut object.vertex_data);
                 indices_data.append(&mut new_indices_data);
             }
 
             _ => {
-                self.offset = object.vertex_data.len() / object.vertex_len;
+                self.offset = object.vertex_data.len() / object.layout.floats();
                 self.vertex_data = Some(object.vertex_data);
                 self.indices = Some(object.indices);
             }
         }
+        StaticObjectId(self.objects.len() - 1)
     }
 
-    pub fn send_data(&mut self, gl: Rc<GlFns>, texture_png_path: &str) {
-        if self.vertex_data.is_none() || self.indices.is_none() {
-            panic!("Data is empty!");
+    // Hidden objects stay in the buffers, they are only left out of the draw call
+    pub fn set_visible(&mut self, id: StaticObjectId, visible: bool) {
+        match self.objects.get_mut(id.0) {
+            Some(object) => {
+                if object.visible != visible {
+                    object.visible = visible;
+                    self.draw_ranges = None;
+                }
+            }
+            None => {
+                panic!("No object {:?} in StaticBatch!", id);
+            }
+        }
+    }
+
+    pub fn is_visible(&self, id: StaticObjectId) -> bool {
+        self.objects.get(id.0).is_some_and(|object| object.visible)
+    }
+
+    fn draw_ranges(&mut self) {
+        if self.draw_ranges.is_some() {
+            return;
         }
+        let objects = self
+            .objects
+            .iter()
+            .map(|object| object.drawn(&self.segments));
+        self.draw_ranges = Some(build_draw_ranges(objects, None).0);
+    }
+
+    pub fn send_data(&mut self, gl: Rc<GlFns>, texture_png_path: Option<&str>) {
+        let texture = create_batch_texture(gl.clone(), texture_png_path);
+        self.send_data_with_texture(gl, texture);
+    }
+
+    // Texture made by the caller, so many batches can share one
+    pub fn send_data_with_texture(&mut self, gl: Rc<GlFns>, texture: u32) {
+        let (vertex_data, indices) = match (&self.vertex_data, &self.indices) {
+            (Some(vertex_data), Some(indices)) => (vertex_data, indices),
+            _ if !self.has_cpu_data() => {
+                panic!("StaticBatch dropped its CPU data, it can not be sent again!");
+            }
+            _ => {
+                panic!("Data is empty!");
+            }
+        };
         let vao = helper::VertexArray::new(gl.clone()).expect("Couldn`t make a VAO");
         vao.bind();
 
         let ebo = helper::Buffer::new(gl.clone()).expect("Couldn't make a EBO");
         ebo.bind(GL_ELEMENT_ARRAY_BUFFER);
 
+        // u32 indices are uploaded straight from indices, u16 ones are converted
+        let index_data: Cow<[u8]> = match self.segments.index_type {
+            IndexType::U32 => encode_indices(indices, 0, IndexType::U32),
+            IndexType::U16 => Cow::Owned(
+                self.objects
+                    .iter()
+                    .flat_map(|object| {
+                        encode_indices(
+                            &indices[object.index_offset..object.index_offset + object.index_count],
+                            self.segments.base_vertex(object.segment),
+                            IndexType::U16,
+                        )
+                        .into_owned()
+                    })
+                    .collect(),
+            ),
+        };
         helper::buffer_data(
             gl.clone(),
             GL_ELEMENT_ARRAY_BUFFER,
-            bytemuck::cast_slice(&self.indices.clone().unwrap()),
+            &index_data,
             GL_STATIC_DRAW,
         );
 
@@ -153,60 +567,150 @@ impl StaticBatch {
         helper::buffer_data(
             gl.clone(),
             GL_ARRAY_BUFFER,
-            bytemuck::cast_slice(&self.vertex_data.clone().unwrap()),
+            bytemuck::cast_slice(vertex_data),
             GL_STATIC_DRAW,
         );
 
-        unsafe {
-            gl.VertexAttribPointer(
-                0,
-                3,
-                GL_FLOAT,
-                0,
-                VERTEX_LEN.try_into().unwrap(),
-                0 as *const _,
-            );
-            gl.EnableVertexAttribArray(0);
-
-            gl.VertexAttribPointer(
-                1,
-                3,
-                GL_FLOAT,
-                0,
-                VERTEX_LEN.try_into().unwrap(),
-                size_of::<[f32; 3]>() as *const _,
-            );
-            gl.EnableVertexAttribArray(1);
-
-            gl.VertexAttribPointer(
-                2,
-                2,
-                GL_FLOAT,
-                0,
-                VERTEX_LEN.try_into().unwrap(),
-                size_of::<[f32; 6]>() as *const _,
-            );
-            gl.EnableVertexAttribArray(2);
-        }
+        self.layout.as_ref().unwrap().apply(&gl);
         self.vao = Some(vao);
         self.vbo = Some(vbo);
         self.ebo = Some(ebo);
-        create_texture(gl, texture_png_path);
+        self.texture = Some(texture);
+        if !self.keep_cpu_data {
+            self.vertex_data = None;
+            self.indices = None;
+        }
+    }
+
+    // Drops GPU buffers, vertices stay on the CPU so send_data can upload them again (unless
+    // set_keep_cpu_data(false) dropped them)
+    pub fn release(&mut self) {
+        self.vao = None;
+        self.vbo = None;
+        self.ebo = None;
+        self.texture = None;
+    }
+
+    pub fn is_sent(&self) -> bool {
+        self.vao.is_some()
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    // Reorders triangles of every object (each level of detail on its own) for the vertex cache
+    // and then its vertices for fetch, has to run before send_data
+    // ACMR in the report is for the full meshes, as they are drawn by default
+    pub fn optimize(&mut self) -> CacheReport {
+        if self.is_sent() {
+            panic!("optimize has to run before send_data!");
+        }
+        let (vertex_data, indices) = match (&mut self.vertex_data, &mut self.indices) {
+            (Some(vertex_data), Some(indices)) => (vertex_data, indices),
+            _ if !self.objects.is_empty() => {
+                panic!("StaticBatch dropped its CPU data, there is nothing to optimize!");
+            }
+            _ => {
+                return CacheReport {
+                    before: 0.0,
+                    after: 0.0,
+                };
+            }
+        };
+        let floats = self.layout.as_ref().unwrap().floats();
+        let full_meshes = |indices: &[u32]| -> Vec<u32> {
+            self.objects
+                .iter()
+                .flat_map(|object| {
+                    let lod = &object.lods[0];
+                    indices[object.index_offset + lod.start..object.index_offset + lod.end]
+                        .iter()
+                        .copied()
+                })
+                .collect()
+        };
+        let before = acmr(&full_meshes(indices), ACMR_CACHE_SIZE);
+
+        for object in &self.objects {
+            let base = object.vertex_offset as u32;
+            let object_vertices = &mut vertex_data[object.vertex_offset * floats
+                ..(object.vertex_offset + object.vertex_count) * floats];
+            let object_indices =
+                &mut indices[object.index_offset..object.index_offset + object.index_count];
+            object_indices.iter_mut().for_each(|index| *index -= base);
+            for lod in &object.lods {
+                let optimized =
+                    optimize_vertex_cache(&object_indices[lod.clone()], object.vertex_count);
+                object_indices[lod.clone()].copy_from_slice(&optimized);
+            }
+            optimize_vertex_fetch(floats, object_vertices, object_indices);
+            object_indices.iter_mut().for_each(|index| *index += base);
+        }
+
+        CacheReport {
+            before,
+            after: acmr(&full_meshes(indices), ACMR_CACHE_SIZE),
+        }
+    }
+
+    pub fn set_lod_settings(&mut self, lod_settings: Option<LodSettings>) {
+        let lods = self.objects.iter_mut().map(|object| &mut object.lod);
+        apply_lod_settings(&mut self.lod_settings, lod_settings, lods);
+        self.draw_ranges = None;
+    }
+
+    pub fn update_lods(&mut self, camera_position: Vec3) {
+        let objects = self
+            .objects
+            .iter_mut()
+            .map(|object| (&object.hitbox, object.lods.len(), &mut object.lod));
+        if select_lods(&self.lod_settings, objects, camera_position) {
+            self.draw_ranges = None;
+        }
+    }
+
+    pub fn lod(&self, id: StaticObjectId) -> Option<usize> {
+        self.objects.get(id.0).map(|object| object.lod)
+    }
+
+    pub fn draw(&mut self, gl: Rc<GlFns>) {
+        self.culled = 0;
+        self.draw_ranges();
+        let draw_ranges = self.draw_ranges.take().unwrap();
+        self.draw_index_ranges(&gl, &draw_ranges);
+        self.draw_ranges = Some(draw_ranges);
     }
 
-    pub fn draw(&self, gl: Rc<GlFns>) {
-        match (&self.indices, &self.ebo, &self.vao, &self.vbo) {
-            (Some(indices), Some(ebo), Some(vao), Some(vbo)) => unsafe {
+    // Same as draw, objects with hitbox outside the frustum are skipped and counted in culled
+    pub fn draw_culled(&mut self, gl: Rc<GlFns>, frustum: &Frustum) {
+        let objects = self
+            .objects
+            .iter()
+            .map(|object| object.drawn(&self.segments));
+        let (draw_ranges, culled) = build_draw_ranges(objects, Some(frustum));
+        self.culled = culled;
+        self.draw_index_ranges(&gl, &draw_ranges);
+    }
+
+    pub fn culled(&self) -> usize {
+        self.culled
+    }
+
+    fn draw_index_ranges(&self, gl: &GlFns, draw_ranges: &[(Range<usize>, usize)]) {
+        match (&self.ebo, &self.vao, &self.vbo, self.texture) {
+            (Some(ebo), Some(vao), Some(vbo), Some(texture)) => {
+                helper::bind_texture(gl, texture);
                 vao.bind();
                 ebo.bind(GL_ELEMENT_ARRAY_BUFFER);
                 vbo.bind(GL_ARRAY_BUFFER);
-                gl.DrawElements(
-                    GL_TRIANGLES,
-                    indices.len().try_into().unwrap(), // rapair it later on
-                    GL_UNSIGNED_INT,
-                    0 as *const _,
+                helper::draw_index_ranges(
+                    gl,
+                    draw_ranges,
+                    self.segments.index_type.gl_type(),
+                    self.segments.index_type.size(),
                 );
-            },
+            }
 
             _ => {
                 panic!("Shit happened!");
@@ -222,153 +726,804 @@ impl StaticBatch {
     2. keep Offsets in hashmap
 */
 
+// Absolute placement of an object, applied to its local-space mesh
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Rotor3,
+    pub scale: Vec3,
+}
+
+impl Transform {
+    pub fn from_translation(translation: Vec3) -> Self {
+        Self {
+            translation,
+            rotation: Rotor3::identity(),
+            scale: Vec3::one(),
+        }
+    }
+
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_translation(self.translation)
+            * self.rotation.into_matrix().into_homogeneous()
+            * Mat4::from_nonuniform_scale(self.scale)
+    }
+
+    // Splits affine matrix into translation, rotation and scale, shear is lost
+    pub fn from_matrix(mat: Mat4) -> Self {
+        let translation = mat.extract_translation();
+        let mut scale = Vec3::new(
+            mat.cols[0].xyz().mag(),
+            mat.cols[1].xyz().mag(),
+            mat.cols[2].xyz().mag(),
+        );
+        // mirroring is kept in scale, rotation has to stay proper
+        if mat.determinant() < 0.0 {
+            scale.x = -scale.x;
+        }
+        let rotation = Mat3::new(
+            mat.cols[0].xyz() / scale.x,
+            mat.cols[1].xyz() / scale.y,
+            mat.cols[2].xyz() / scale.z,
+        )
+        .into_rotor3();
+
+        Self {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+}
+
 // Shitfuck struct
 pub struct Offset {
+    name: Option<String>,
     offset: usize,
-    vertex_data: Vec<f32>,
-    position: Vec3,
+    index_offset: usize,
+    index_count: usize,
+    segment: usize,
+    // relative to index_offset, index_count covers all of them
+    lods: Vec<Range<usize>>,
+    lod: usize,
+    // pristine mesh around object origin, the only CPU copy of the object, world vertices and
+    // hitbox are always made from it
+    local_vertex_data: Vec<f32>,
+    local_hitbox: Box3D,
+    transform: Transform,
     hitbox: Box3D,
+    // opt-in exact shape used by colide instead of the hitbox, local one is in space of the local
+    // mesh, world one follows transform (rotation too)
+    local_collider: Option<Collider>,
+    collider: Option<Collider>,
+    // leaf of the object in broadphase
+    proxy: ProxyId,
+    // slot in object_transforms, only in TransformMode::Gpu
+    slot: Option<usize>,
+    visible: bool,
+    // moved since its vertices were uploaded, it is in dirty_objects
+    dirty: bool,
+}
+
+impl Offset {
+    fn drawn<'a>(&'a self, segments: &IndexSegments) -> DrawnObject<'a> {
+        DrawnObject {
+            index_offset: self.index_offset,
+            lod: &self.lods[self.lod],
+            base_vertex: segments.base_vertex(self.segment),
+            hitbox: &self.hitbox,
+            visible: self.visible,
+        }
+    }
+
+    // Vertices as they are on the GPU, world ones (TransformMode::Cpu) are made in scratch
+    fn gpu_vertex_data<'a>(
+        &'a self,
+        layout: &VertexLayout,
+        scratch: &'a mut Vec<f32>,
+    ) -> &'a [f32] {
+        match self.slot {
+            Some(_) => &self.local_vertex_data,
+            None => {
+                scratch.resize(self.local_vertex_data.len(), 0.0);
+                transform_vertices(
+                    layout,
+                    &self.local_vertex_data,
+                    scratch,
+                    self.transform.matrix(),
+                );
+                scratch
+            }
+        }
+    }
+}
+
+// Copyable reference to an object in DynamicBatch, generation tells apart objects which used the
+// same index, so handle of a removed object stays stale even if its place is reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjectHandle {
+    index: u32,
+    generation: u32,
+}
+
+// Nearest object hit by DynamicBatch::raycast, normal faces the ray
+#[derive(Debug, Clone)]
+pub struct RayHit {
+    pub handle: ObjectHandle,
+    pub name: Option<String>,
+    pub distance: f32,
+    pub point: Vec3,
+    pub normal: Vec3,
+}
+
+struct ObjectEntry {
+    generation: u32,
+    offset: Option<Offset>,
+}
+
+// Cpu: move_delta and change_position rewrite vertices of the object, all of them are uploaded
+// by the next draw
+// Gpu: vertices keep object index, vert.glsl reads model matrix of the object from a texture
+// buffer, moving uploads 64 bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransformMode {
+    Cpu,
+    Gpu,
+}
+
+// How vertices of moved objects are written by the flush before draw
+// SubData: BufferSubData of the moved ranges, waits for draws still reading the buffer
+// Orphan: the buffer gets new storage (the old one stays with draws reading it) and every object
+// is written again, no wait but the whole batch is transformed and uploaded, so it pays off when
+// most objects move every frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VertexUpload {
+    SubData,
+    Orphan,
 }
 
+// Vertices are kept only in Offsets, indices of every object are in one vector
 pub struct DynamicBatch {
-    vertex_data: Option<Vec<f32>>,
     indices: Option<Vec<u32>>,
     offset: usize,
-    offset_map: HashMap<String, Offset>,
+    layout: Option<VertexLayout>,
+    objects: Vec<ObjectEntry>,
+    segments: IndexSegments,
+    free_objects: Vec<u32>,
+    // optional secondary index, only objects consumed with a name are in it
+    names: HashMap<String, ObjectHandle>,
+    // world hitboxes of every object, kept up to date by moves
+    broadphase: AabbTree<ObjectHandle>,
+    transform_mode: TransformMode,
+    object_transforms: Vec<Mat4>,
+    free_slots: Vec<usize>,
+    dirty_transforms: Option<Range<usize>>,
+    // indices of objects moved in TransformMode::Cpu, uploaded together by the next draw
+    dirty_objects: Vec<u32>,
+    vertex_upload: VertexUpload,
+    // merged index ranges (with base vertex) of visible objects, None after objects or
+    // visibility changed
+    draw_ranges: Option<Vec<(Range<usize>, usize)>>,
+    culled: usize,
+    lod_settings: Option<LodSettings>,
+    transform_capacity: usize,
+    transform_buffer: Option<helper::Buffer>,
+    transform_texture: Option<u32>,
+    // GPU buffers are bigger than the data, capacities are in vertices and indices
+    vertex_capacity: usize,
+    index_capacity: usize,
+    uploaded_vertices: usize,
+    uploaded_indices: usize,
     ebo: Option<helper::Buffer>,
     vbo: Option<helper::Buffer>,
     vao: Option<helper::VertexArray>,
+    texture: Option<u32>,
+}
+
+const MIN_CAPACITY: usize = 1024;
+// texture unit of the samplerBuffer with model matrices, set object_transforms uniform to it
+pub const OBJECT_TRANSFORMS_UNIT: u32 = 1;
+
+// Doubles capacity until needed fits, so appending objects one by one is amortized
+fn grown_capacity(capacity: usize, needed: usize) -> usize {
+    let mut capacity = capacity.max(MIN_CAPACITY);
+    while capacity < needed {
+        capacity *= 2;
+    }
+    capacity
 }
 
 //offset is offset of Vertex elements( if vertex is vertices and normals an uv then one element is
 //8 f32 long)
 impl DynamicBatch {
     pub fn new() -> Self {
+        Self::with_transform_mode(TransformMode::Cpu)
+    }
+
+    pub fn with_transform_mode(transform_mode: TransformMode) -> Self {
         Self {
-            vertex_data: None,
             indices: None,
             offset: 0, // Its just offset needed to add new BatchObject
-            offset_map: HashMap::new(),
+            layout: None,
+            objects: vec![],
+            segments: IndexSegments::new(),
+            free_objects: vec![],
+            names: HashMap::new(),
+            broadphase: AabbTree::new(),
+            transform_mode,
+            object_transforms: vec![],
+            free_slots: vec![],
+            dirty_transforms: None,
+            dirty_objects: vec![],
+            vertex_upload: VertexUpload::SubData,
+            draw_ranges: None,
+            culled: 0,
+            lod_settings: None,
+            transform_capacity: 0,
+            transform_buffer: None,
+            transform_texture: None,
+            vertex_capacity: 0,
+            index_capacity: 0,
+            uploaded_vertices: 0,
+            uploaded_indices: 0,
             vbo: None,
             ebo: None,
             vao: None,
+            texture: None,
         }
     }
 
-    pub fn consume_object(&mut self, mut object: BatchObject, name: &str) {
-        match self.offset_map.get(name) {
-            Some(_value) => {
-                panic!("Name already taken in offset_map!");
-            }
-            None => {
-                self.offset_map.insert(
-                    name.to_string(),
-                    Offset {
-                        offset: self.offset * VERTEX_LEN,
-                        vertex_data: object.vertex_data.clone(),
-                        position: object.position,
-                        hitbox: object.hitbox,
-                    },
-                );
-            }
+    pub fn consume_named(
+        &mut self,
+        object: BatchObject,
+        name: &str,
+    ) -> Result<ObjectHandle, String> {
+        if self.names.contains_key(name) {
+            return Err(format!("Name {} already taken in DynamicBatch", name));
         }
+        let handle = self.consume_object(object);
+        self.names.insert(name.to_string(), handle);
+        self.offset_mut(handle).unwrap().name = Some(name.to_string());
+        Ok(handle)
+    }
 
-        match (&mut self.vertex_data, &mut self.indices) {
-            (Some(vertex_data), Some(indices_data)) => {
-                let offset = self.offset as u32;
-                let mut new_indices_data = object.indices;
-                new_indices_data.iter_mut().for_each(|elem| *elem += offset);
-                self.offset += object.vertex_data.len() / object.vertex_len;
-
-                vertex_data.append(&mut object.vertex_data);
-                indices_data.append(&mut new_indices_data);
+    pub fn consume_object(&mut self, mut object: BatchObject) -> ObjectHandle {
+        check_layout(&mut self.layout, &object.layout);
+        if self.transform_mode == TransformMode::Gpu
+            && object.layout.attribute(Semantic::ObjectIndex).is_none()
+        {
+            panic!("TransformMode::Gpu needs Semantic::ObjectIndex in the layout!");
+        }
+        let handle = match self.free_objects.pop() {
+            Some(index) => ObjectHandle {
+                index,
+                generation: self.objects[index as usize].generation,
+            },
+            None => {
+                self.objects.push(ObjectEntry {
+                    generation: 0,
+                    offset: None,
+                });
+                ObjectHandle {
+                    index: (self.objects.len() - 1) as u32,
+                    generation: 0,
+                }
             }
+        };
 
-            _ => {
-                self.offset = object.vertex_data.len() / object.vertex_len;
-                self.vertex_data = Some(object.vertex_data);
-                self.indices = Some(object.indices);
+        // BatchObject has only translation baked in, taking it away gives local mesh
+        let mut local_vertex_data = object.vertex_data;
+        translate_positions(&object.layout, &mut local_vertex_data, -object.position);
+        let mut local_hitbox = object.hitbox;
+        local_hitbox.move_delta(-object.position);
+        let transform = Transform::from_translation(object.position);
+
+        let slot = match self.transform_mode {
+            TransformMode::Cpu => None,
+            TransformMode::Gpu => Some(self.allocate_slot()),
+        };
+        if let Some(slot) = slot {
+            for vertex in local_vertex_data.chunks_exact_mut(object.layout.floats()) {
+                object
+                    .layout
+                    .write(vertex, Semantic::ObjectIndex, &[slot as f32]);
             }
+            // GPU gets local vertices, model matrix places them
+            self.object_transforms[slot] = transform.matrix();
+        }
+        let vertex_count = local_vertex_data.len() / object.layout.floats();
+        let (segment, switched) = self.segments.add(self.offset, vertex_count);
+        if switched {
+            // every uploaded index is u16, the element buffer is made again with u32 ones
+            self.uploaded_indices = 0;
+            self.index_capacity = 0;
         }
+        self.objects[handle.index as usize].offset = Some(Offset {
+            name: None,
+            offset: self.offset * object.layout.stride(),
+            index_offset: self.indices.as_ref().map_or(0, |indices| indices.len()),
+            index_count: object.indices.len(),
+            segment,
+            lods: object.lods.clone(),
+            lod: 0,
+            local_vertex_data,
+            local_hitbox,
+            transform,
+            hitbox: object.hitbox,
+            local_collider: None,
+            collider: None,
+            proxy: self.broadphase.insert(object.hitbox, handle),
+            slot,
+            visible: true,
+            dirty: false,
+        });
+        self.draw_ranges = None;
+
+        let offset = self.offset as u32;
+        self.indices
+            .get_or_insert_with(Vec::new)
+            .extend(object.indices.iter().map(|index| index + offset));
+        self.offset += vertex_count;
+        handle
     }
 
-    pub fn send_data(&mut self, gl: Rc<GlFns>, texture_png_path: &str) {
-        if self.vertex_data.is_none() || self.indices.is_none() {
-            panic!("Data is empty!");
-        }
-        let vao = helper::VertexArray::new(gl.clone()).expect("Couldn`t make a VAO");
-        vao.bind();
+    // Every part of the object gets its own entry named "name/part_name", so parts can be moved
+    // one by one
+    pub fn consume_parts(
+        &mut self,
+        object: BatchObject,
+        name: &str,
+    ) -> Result<Vec<ObjectHandle>, String> {
+        object
+            .split_parts()
+            .into_iter()
+            .map(|(part_name, part)| self.consume_named(part, &format!("{}/{}", name, part_name)))
+            .collect()
+    }
 
-        let ebo = helper::Buffer::new(gl.clone()).expect("Couldn't make a EBO");
-        ebo.bind(GL_ELEMENT_ARRAY_BUFFER);
+    pub fn handle(&self, name: &str) -> Option<ObjectHandle> {
+        self.names.get(name).copied()
+    }
 
-        helper::buffer_data(
-            gl.clone(),
-            GL_ELEMENT_ARRAY_BUFFER,
-            bytemuck::cast_slice(&self.indices.clone().unwrap()),
-            GL_STATIC_DRAW,
-        );
+    pub fn name(&self, handle: ObjectHandle) -> Option<&str> {
+        self.offset(handle).ok()?.name.as_deref()
+    }
 
-        let vbo = helper::Buffer::new(gl.clone()).expect("Couldn't make a VBO");
-        vbo.bind(GL_ARRAY_BUFFER);
+    pub fn contains(&self, handle: ObjectHandle) -> bool {
+        self.offset(handle).is_ok()
+    }
 
-        helper::buffer_data(
-            gl.clone(),
-            GL_ARRAY_BUFFER,
-            bytemuck::cast_slice(&self.vertex_data.clone().unwrap()),
-            GL_STATIC_DRAW,
-        );
+    // every live object, order is not specified
+    pub fn handles(&self) -> impl Iterator<Item = ObjectHandle> + '_ {
+        self.objects
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.offset.is_some())
+            .map(|(index, entry)| ObjectHandle {
+                index: index as u32,
+                generation: entry.generation,
+            })
+    }
 
-        unsafe {
-            gl.VertexAttribPointer(
-                0,
-                3,
-                GL_FLOAT,
-                0,
-                size_of::<[f32; 8]>().try_into().unwrap(),
-                0 as *const _,
+    fn offset(&self, handle: ObjectHandle) -> Result<&Offset, String> {
+        match self.objects.get(handle.index as usize) {
+            Some(ObjectEntry {
+                generation,
+                offset: Some(offset),
+            }) if *generation == handle.generation => Ok(offset),
+            _ => Err(format!("Stale handle {:?} in DynamicBatch", handle)),
+        }
+    }
+
+    fn offset_mut(&mut self, handle: ObjectHandle) -> Result<&mut Offset, String> {
+        match self.objects.get_mut(handle.index as usize) {
+            Some(ObjectEntry {
+                generation,
+                offset: Some(offset),
+            }) if *generation == handle.generation => Ok(offset),
+            _ => Err(format!("Stale handle {:?} in DynamicBatch", handle)),
+        }
+    }
+
+    fn offsets(&self) -> impl Iterator<Item = &Offset> {
+        self.objects
+            .iter()
+            .filter_map(|entry| entry.offset.as_ref())
+    }
+
+    fn offsets_mut(&mut self) -> impl Iterator<Item = &mut Offset> {
+        self.objects
+            .iter_mut()
+            .filter_map(|entry| entry.offset.as_mut())
+    }
+
+    fn allocate_slot(&mut self) -> usize {
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => {
+                self.object_transforms.push(Mat4::identity());
+                self.object_transforms.len() - 1
+            }
+        };
+        self.object_transforms[slot] = Mat4::identity();
+        self.mark_transform_dirty(slot);
+        slot
+    }
+
+    fn mark_transform_dirty(&mut self, slot: usize) {
+        self.dirty_transforms = Some(match self.dirty_transforms.take() {
+            Some(range) => range.start.min(slot)..range.end.max(slot + 1),
+            None => slot..slot + 1,
+        });
+    }
+
+    // Objects consumed after send_data are uploaded by the next draw (or upload_pending)
+    pub fn send_data(&mut self, gl: Rc<GlFns>, texture_png_path: Option<&str>) {
+        if self.indices.is_none() {
+            panic!("Data is empty!");
+        }
+        let vao = helper::VertexArray::new(gl.clone()).expect("Couldn`t make a VAO");
+        let ebo = helper::Buffer::new(gl.clone()).expect("Couldn't make a EBO");
+        let vbo = helper::Buffer::new(gl.clone()).expect("Couldn't make a VBO");
+
+        self.vao = Some(vao);
+        self.vbo = Some(vbo);
+        self.ebo = Some(ebo);
+        self.vertex_capacity = 0;
+        self.index_capacity = 0;
+        self.uploaded_vertices = 0;
+        self.uploaded_indices = 0;
+        if self.transform_mode == TransformMode::Gpu {
+            self.transform_buffer =
+                Some(helper::Buffer::new(gl.clone()).expect("Couldn't make a TBO"));
+            self.transform_capacity = 0;
+            self.dirty_transforms = Some(0..self.object_transforms.len());
+        }
+        self.upload_pending(gl.clone());
+
+        self.texture = Some(create_batch_texture(gl, texture_png_path));
+    }
+
+    // Appends vertices and indices which are not on the GPU yet, buffers are reallocated (with
+    // GPU side copy of old contents) only when capacity runs out
+    // Vertices of moved objects are written before that
+    pub fn upload_pending(&mut self, gl: Rc<GlFns>) {
+        self.flush_dirty_objects(gl.clone());
+        let indices = match &self.indices {
+            Some(indices) => indices,
+            None => return,
+        };
+        let (vao, mut vbo, mut ebo) = match (&self.vao, self.vbo.take(), self.ebo.take()) {
+            (Some(vao), Some(vbo), Some(ebo)) => (vao, vbo, ebo),
+            (_, vbo, ebo) => {
+                self.vbo = vbo;
+                self.ebo = ebo;
+                return;
+            }
+        };
+        let layout = self.layout.as_ref().unwrap();
+        let index_size = self.segments.index_type.size();
+        vao.bind();
+
+        if self.offset > self.vertex_capacity {
+            let capacity = grown_capacity(self.vertex_capacity, self.offset);
+            let new_vbo = helper::Buffer::new(gl.clone()).expect("Couldn't make a VBO");
+            new_vbo.bind(GL_ARRAY_BUFFER);
+            helper::allocate_buffer(
+                gl.clone(),
+                GL_ARRAY_BUFFER,
+                capacity * layout.stride(),
+                GL_DYNAMIC_DRAW,
+            );
+            helper::copy_buffer(
+                gl.clone(),
+                &vbo,
+                &new_vbo,
+                self.uploaded_vertices * layout.stride(),
             );
-            gl.EnableVertexAttribArray(0);
+            // attribute pointers keep the old buffer until they are set again
+            new_vbo.bind(GL_ARRAY_BUFFER);
+            layout.apply(&gl);
+            self.vertex_capacity = capacity;
+            vbo = new_vbo;
+        }
 
-            gl.VertexAttribPointer(
-                1,
-                3,
-                GL_FLOAT,
-                0,
-                size_of::<[f32; 8]>().try_into().unwrap(),
-                size_of::<[f32; 3]>() as *const _,
+        if indices.len() > self.index_capacity {
+            let capacity = grown_capacity(self.index_capacity, indices.len());
+            let new_ebo = helper::Buffer::new(gl.clone()).expect("Couldn't make a EBO");
+            // bound with the VAO, so the VAO switches to the new element buffer
+            new_ebo.bind(GL_ELEMENT_ARRAY_BUFFER);
+            helper::allocate_buffer(
+                gl.clone(),
+                GL_ELEMENT_ARRAY_BUFFER,
+                capacity * index_size,
+                GL_DYNAMIC_DRAW,
+            );
+            helper::copy_buffer(
+                gl.clone(),
+                &ebo,
+                &new_ebo,
+                self.uploaded_indices * index_size,
             );
-            gl.EnableVertexAttribArray(1);
+            self.index_capacity = capacity;
+            ebo = new_ebo;
+        }
 
-            gl.VertexAttribPointer(
-                2,
-                2,
-                GL_FLOAT,
-                0,
-                size_of::<[f32; 8]>().try_into().unwrap(),
-                size_of::<[f32; 6]>() as *const _,
+        if self.uploaded_vertices < self.offset {
+            vbo.bind(GL_ARRAY_BUFFER);
+            // straight from the Offsets, removed objects leave garbage nothing draws
+            let mut scratch = vec![];
+            for elem in self
+                .objects
+                .iter()
+                .filter_map(|entry| entry.offset.as_ref())
+                .filter(|elem| elem.offset >= self.uploaded_vertices * layout.stride())
+            {
+                helper::buffer_sub_data(
+                    gl.clone(),
+                    GL_ARRAY_BUFFER,
+                    elem.offset,
+                    bytemuck::cast_slice(elem.gpu_vertex_data(layout, &mut scratch)),
+                );
+            }
+            self.uploaded_vertices = self.offset;
+        }
+
+        if self.uploaded_indices < indices.len() {
+            let index_data = match self.segments.index_type {
+                // u32 indices are absolute, they are uploaded straight from indices
+                IndexType::U32 => {
+                    encode_indices(&indices[self.uploaded_indices..], 0, IndexType::U32)
+                }
+                // indices of every object are relative to base vertex of its segment, ones of
+                // removed objects stay zero (nothing draws them)
+                IndexType::U16 => {
+                    let mut index_data =
+                        vec![0; (indices.len() - self.uploaded_indices) * index_size];
+                    for elem in self
+                        .objects
+                        .iter()
+                        .filter_map(|entry| entry.offset.as_ref())
+                        .filter(|elem| elem.index_offset >= self.uploaded_indices)
+                    {
+                        let start = (elem.index_offset - self.uploaded_indices) * index_size;
+                        let encoded = encode_indices(
+                            &indices[elem.index_offset..elem.index_offset + elem.index_count],
+                            self.segments.base_vertex(elem.segment),
+                            IndexType::U16,
+                        );
+                        index_data[start..start + encoded.len()].copy_from_slice(&encoded);
+                    }
+                    Cow::Owned(index_data)
+                }
+            };
+            ebo.bind(GL_ELEMENT_ARRAY_BUFFER);
+            helper::buffer_sub_data(
+                gl.clone(),
+                GL_ELEMENT_ARRAY_BUFFER,
+                self.uploaded_indices * index_size,
+                &index_data,
             );
-            gl.EnableVertexAttribArray(2);
+            self.uploaded_indices = indices.len();
         }
-        self.vao = Some(vao);
+
         self.vbo = Some(vbo);
         self.ebo = Some(ebo);
-        create_texture(gl, texture_png_path);
+        self.upload_transforms(gl);
+    }
+
+    pub fn set_vertex_upload(&mut self, vertex_upload: VertexUpload) {
+        self.vertex_upload = vertex_upload;
+    }
+
+    // Objects moved since the last flush are sorted by offset, neighbours in the buffer are
+    // written with one call
+    fn flush_dirty_objects(&mut self, gl: Rc<GlFns>) {
+        let vbo = match &self.vbo {
+            Some(vbo) if !self.dirty_objects.is_empty() => vbo,
+            _ => return,
+        };
+        let layout = self.layout.as_ref().unwrap();
+        let uploaded = self.uploaded_vertices * layout.stride();
+        let mut dirty: Vec<usize> = vec![];
+        for index in self.dirty_objects.drain(..) {
+            // removed objects are skipped, the ones not uploaded yet go with upload_pending
+            if let Some(elem) = self.objects[index as usize].offset.as_mut()
+                && elem.dirty
+            {
+                elem.dirty = false;
+                if elem.offset < uploaded {
+                    dirty.push(index as usize);
+                }
+            }
+        }
+        let mut dirty: Vec<&Offset> = dirty
+            .into_iter()
+            .map(|index| self.objects[index].offset.as_ref().unwrap())
+            .collect();
+        dirty.sort_by_key(|elem| elem.offset);
+        // nothing is on the GPU (right after compact), upload_pending writes every object
+        if uploaded == 0 {
+            return;
+        }
+
+        vbo.bind(GL_ARRAY_BUFFER);
+        let mut scratch = vec![];
+        if self.vertex_upload == VertexUpload::Orphan {
+            // space of removed objects is left zeroed, nothing draws it
+            let mut vertex_data = vec![0.0; uploaded / size_of::<f32>()];
+            for elem in self
+                .objects
+                .iter()
+                .filter_map(|entry| entry.offset.as_ref())
+            {
+                if elem.offset < uploaded {
+                    let start = elem.offset / size_of::<f32>();
+                    let data = elem.gpu_vertex_data(layout, &mut scratch);
+                    vertex_data[start..start + data.len()].copy_from_slice(data);
+                }
+            }
+            helper::allocate_buffer(
+                gl.clone(),
+                GL_ARRAY_BUFFER,
+                self.vertex_capacity * layout.stride(),
+                GL_DYNAMIC_DRAW,
+            );
+            helper::buffer_sub_data(gl, GL_ARRAY_BUFFER, 0, bytemuck::cast_slice(&vertex_data));
+            return;
+        }
+        let mut vertex_data: Vec<f32> = vec![];
+        let mut start = 0;
+        for (at, elem) in dirty.iter().enumerate() {
+            if vertex_data.is_empty() {
+                start = elem.offset;
+            }
+            vertex_data.extend_from_slice(elem.gpu_vertex_data(layout, &mut scratch));
+            let end = start + size_of_val(vertex_data.as_slice());
+            if dirty.get(at + 1).is_some_and(|next| next.offset == end) {
+                continue;
+            }
+            helper::buffer_sub_data(
+                gl.clone(),
+                GL_ARRAY_BUFFER,
+                start,
+                bytemuck::cast_slice(&vertex_data),
+            );
+            vertex_data.clear();
+        }
+    }
+
+    fn upload_transforms(&mut self, gl: Rc<GlFns>) {
+        let (buffer, range) = match (&self.transform_buffer, self.dirty_transforms.take()) {
+            (Some(buffer), Some(range)) => (buffer, range),
+            (_, range) => {
+                self.dirty_transforms = range;
+                return;
+            }
+        };
+        buffer.bind(GL_TEXTURE_BUFFER);
+
+        if self.object_transforms.len() > self.transform_capacity {
+            // reallocating storage of the buffer, the texture is attached again to be safe
+            self.transform_capacity =
+                grown_capacity(self.transform_capacity, self.object_transforms.len());
+            helper::allocate_buffer(
+                gl.clone(),
+                GL_TEXTURE_BUFFER,
+                self.transform_capacity * size_of::<Mat4>(),
+                GL_DYNAMIC_DRAW,
+            );
+            helper::buffer_sub_data(
+                gl.clone(),
+                GL_TEXTURE_BUFFER,
+                0,
+                bytemuck::cast_slice(&self.object_transforms),
+            );
+            self.transform_texture = Some(match self.transform_texture {
+                Some(texture) => {
+                    helper::attach_buffer_texture(&gl, texture, buffer);
+                    texture
+                }
+                None => helper::create_buffer_texture(gl, buffer),
+            });
+            return;
+        }
+
+        helper::buffer_sub_data(
+            gl,
+            GL_TEXTURE_BUFFER,
+            range.start * size_of::<Mat4>(),
+            bytemuck::cast_slice(&self.object_transforms[range]),
+        );
     }
 
-    pub fn draw(&self, gl: Rc<GlFns>) {
-        match (&self.indices, &self.ebo, &self.vao, &self.vbo) {
-            (Some(indices), Some(ebo), Some(vao), Some(vbo)) => unsafe {
+    pub fn set_visible(&mut self, handle: ObjectHandle, visible: bool) -> Result<(), String> {
+        let elem = self.offset_mut(handle)?;
+        if elem.visible != visible {
+            elem.visible = visible;
+            self.draw_ranges = None;
+        }
+        Ok(())
+    }
+
+    pub fn is_visible(&self, handle: ObjectHandle) -> Result<bool, String> {
+        Ok(self.offset(handle)?.visible)
+    }
+
+    // removed objects have no Offset, so their degenerate indices are skipped too
+    fn draw_ranges(&mut self) {
+        if self.draw_ranges.is_some() {
+            return;
+        }
+        let objects = self.offsets().map(|elem| elem.drawn(&self.segments));
+        self.draw_ranges = Some(build_draw_ranges(objects, None).0);
+    }
+
+    pub fn set_lod_settings(&mut self, lod_settings: Option<LodSettings>) {
+        let lods = self
+            .objects
+            .iter_mut()
+            .filter_map(|entry| entry.offset.as_mut())
+            .map(|elem| &mut elem.lod);
+        apply_lod_settings(&mut self.lod_settings, lod_settings, lods);
+        self.draw_ranges = None;
+    }
+
+    pub fn update_lods(&mut self, camera_position: Vec3) {
+        let elems = self
+            .objects
+            .iter_mut()
+            .filter_map(|entry| entry.offset.as_mut())
+            .map(|elem| (&elem.hitbox, elem.lods.len(), &mut elem.lod));
+        if select_lods(&self.lod_settings, elems, camera_position) {
+            self.draw_ranges = None;
+        }
+    }
+
+    pub fn lod(&self, handle: ObjectHandle) -> Result<usize, String> {
+        Ok(self.offset(handle)?.lod)
+    }
+
+    pub fn draw(&mut self, gl: Rc<GlFns>) {
+        self.culled = 0;
+        self.upload_pending(gl.clone());
+        self.draw_ranges();
+        let draw_ranges = self.draw_ranges.take().unwrap();
+        self.draw_index_ranges(&gl, &draw_ranges);
+        self.draw_ranges = Some(draw_ranges);
+    }
+
+    // Same as draw, objects with world hitbox outside the frustum are skipped and counted in
+    // culled
+    pub fn draw_culled(&mut self, gl: Rc<GlFns>, frustum: &Frustum) {
+        self.upload_pending(gl.clone());
+        let objects = self.offsets().map(|elem| elem.drawn(&self.segments));
+        let (draw_ranges, culled) = build_draw_ranges(objects, Some(frustum));
+        self.culled = culled;
+        self.draw_index_ranges(&gl, &draw_ranges);
+    }
+
+    pub fn culled(&self) -> usize {
+        self.culled
+    }
+
+    fn draw_index_ranges(&self, gl: &GlFns, draw_ranges: &[(Range<usize>, usize)]) {
+        match (&self.ebo, &self.vao, &self.vbo, self.texture) {
+            (Some(ebo), Some(vao), Some(vbo), Some(texture)) => {
+                if let Some(transform_texture) = self.transform_texture {
+                    helper::bind_buffer_texture(gl, transform_texture, OBJECT_TRANSFORMS_UNIT);
+                }
+                helper::bind_texture(gl, texture);
                 vao.bind();
                 ebo.bind(GL_ELEMENT_ARRAY_BUFFER);
                 vbo.bind(GL_ARRAY_BUFFER);
-                gl.DrawElements(
-                    GL_TRIANGLES,
-                    indices.len().try_into().unwrap(), // rapair it later on
-                    GL_UNSIGNED_INT,
-                    0 as *const _,
+                helper::draw_index_ranges(
+                    gl,
+                    draw_ranges,
+                    self.segments.index_type.gl_type(),
+                    self.segments.index_type.size(),
                 );
-            },
+            }
 
             _ => {
                 panic!("Shit happened!");
@@ -376,127 +1531,851 @@ impl DynamicBatch {
         }
     }
 
-    //
-    //????????????????????????????????????????????????????????????????????????????
-    //
-    //
-    //
-    //
-    //
-    pub fn move_delta(&mut self, gl: Rc<GlFns>, name: &str, transformation: Mat4) {
-        match self.offset_map.get_mut(name) {
-            Some(elem) => {
-                elem.position = Vec3::from(
-                    transformation
-                        * Vec4 {
-                            x: elem.position.x,
-                            y: elem.position.y,
-                            z: elem.position.z,
-                            w: 1.0
-                        },
-                );
-                elem.hitbox.transformation(transformation);
-                for i in 0..(elem.vertex_data.len() / 8) {
-                    let i = i * 8;
-                    let pos = transformation
-                        * Vec4::new(
-                            elem.vertex_data[i],
-                            elem.vertex_data[i + 1],
-                            elem.vertex_data[i + 2],
-                            1.0,
-                        );
-                    let mut norm = transformation.inversed().transposed()
-                        * Vec4::new(
-                            elem.vertex_data[i + 3],
-                            elem.vertex_data[i + 4],
-                            elem.vertex_data[i + 5],
-                            1.0,
-                        );
-                    norm.normalize();
+    // Transformation is applied on top of current transform of the object, vertices are still
+    // made from the local mesh so nothing accumulates
+    pub fn move_delta(&mut self, handle: ObjectHandle, transformation: Mat4) -> Result<(), String> {
+        let elem = self.offset(handle)?;
+        let transform = Transform::from_matrix(transformation * elem.transform.matrix());
+        self.apply_transform(handle, transform)
+    }
 
-                    elem.vertex_data[i] = pos.x;
-                    elem.vertex_data[i + 1] = pos.y;
-                    elem.vertex_data[i + 2] = pos.z;
+    pub fn change_position(&mut self, handle: ObjectHandle, position: Vec3) -> Result<(), String> {
+        let elem = self.offset(handle)?;
+        let transform = Transform {
+            translation: position,
+            ..elem.transform
+        };
+        self.apply_transform(handle, transform)
+    }
 
-                    elem.vertex_data[i + 3] = norm.x;
-                    elem.vertex_data[i + 4] = norm.y;
-                    elem.vertex_data[i + 5] = norm.z;
-                }
-                unsafe {
-                    gl.BufferSubData(
-                        GL_ARRAY_BUFFER,
-                        (elem.offset) as isize,
-                        (elem.vertex_data.len() * size_of::<f32>()) as isize,
-                        elem.vertex_data.as_ptr() as *const c_void,
-                    );
-                }
+    pub fn set_transform(
+        &mut self,
+        handle: ObjectHandle,
+        translation: Vec3,
+        rotation: Rotor3,
+        scale: Vec3,
+    ) -> Result<(), String> {
+        self.apply_transform(
+            handle,
+            Transform {
+                translation,
+                rotation,
+                scale,
+            },
+        )
+    }
+
+    pub fn get_transform(&self, handle: ObjectHandle) -> Result<Transform, String> {
+        Ok(self.offset(handle)?.transform)
+    }
+
+    // Nothing is uploaded here, vertices of the object are queued for the next draw
+    fn apply_transform(
+        &mut self,
+        handle: ObjectHandle,
+        transform: Transform,
+    ) -> Result<(), String> {
+        let elem = self.offset_mut(handle)?;
+        let model = transform.matrix();
+        elem.transform = transform;
+        elem.hitbox = elem.local_hitbox.transformed(model);
+        elem.collider = elem
+            .local_collider
+            .as_ref()
+            .map(|collider| collider.transformed(model));
+        let (proxy, hitbox, slot) = (elem.proxy, elem.hitbox, elem.slot);
+
+        match slot {
+            Some(slot) => {
+                self.object_transforms[slot] = model;
+                self.mark_transform_dirty(slot);
             }
-            None => {
-                panic!("Shitfuck no name in map, function: move_delta!");
+            None if !elem.dirty => {
+                elem.dirty = true;
+                self.dirty_objects.push(handle.index);
             }
+            None => (),
         }
+        self.broadphase.update(proxy, hitbox);
+        Ok(())
     }
 
-    pub fn change_position(&mut self, gl: Rc<GlFns>, name: &str, position: Vec3) {
-        match self.offset_map.get_mut(name) {
-            Some(elem) => {
-                let change_vec = position - elem.position;
-                elem.position = position;
+    // Object disappears right away (draws leave its indices out), the space it used is given back
+    // only by compact
+    // Handle (and every copy of it) becomes stale
+    pub fn remove_object(&mut self, handle: ObjectHandle) -> Result<(), String> {
+        self.offset(handle)?;
+        let entry = &mut self.objects[handle.index as usize];
+        let elem = entry.offset.take().unwrap();
+        entry.generation = entry.generation.wrapping_add(1);
+        self.free_objects.push(handle.index);
+        self.broadphase.remove(elem.proxy);
+        if let Some(name) = &elem.name {
+            self.names.remove(name);
+        }
+        if let Some(slot) = elem.slot {
+            self.free_slots.push(slot);
+        }
+        self.draw_ranges = None;
+        Ok(())
+    }
 
-                elem.hitbox.move_delta(change_vec);
+    // Vertices and indices no longer used by any object, they are still in the buffers
+    pub fn wasted_vertices(&self) -> usize {
+        let floats = match &self.layout {
+            Some(layout) => layout.floats(),
+            None => return 0,
+        };
+        let used: usize = self
+            .offsets()
+            .map(|elem| elem.local_vertex_data.len() / floats)
+            .sum();
+        self.offset - used
+    }
 
-                for i in 0..(elem.vertex_data.len() / 8) {
-                    let i = i * 8;
-                    elem.vertex_data[i] += change_vec.x;
-                    elem.vertex_data[i + 1] += change_vec.y;
-                    elem.vertex_data[i + 2] += change_vec.z;
-                    let mut norm = Mat4::from_translation(position).inversed().transposed()
-                        * Vec4::new(
-                            elem.vertex_data[i + 3],
-                            elem.vertex_data[i + 4],
-                            elem.vertex_data[i + 5],
-                            1.0,
-                        );
-                    elem.vertex_data[i + 3] = norm.x;
-                    elem.vertex_data[i + 4] = norm.y;
-                    elem.vertex_data[i + 5] = norm.z;
-                }
-                unsafe {
-                    gl.BufferSubData(
-                        GL_ARRAY_BUFFER,
-                        (elem.offset) as isize,
-                        (elem.vertex_data.len() * size_of::<f32>()) as isize,
-                        elem.vertex_data.as_ptr() as *const c_void,
-                    );
+    // Moves every object to the front of the buffers in order of offsets, removed objects are
+    // dropped and the data is uploaded to new buffers sized for what is left (grown like on
+    // consume, so there is room for new objects), an empty batch keeps its old buffers
+    pub fn compact(&mut self, gl: Rc<GlFns>) {
+        let old_indices = match self.indices.take() {
+            Some(indices) => indices,
+            None => return,
+        };
+
+        let layout = self.layout.clone().unwrap();
+        let mut elems: Vec<&mut Offset> = self.offsets_mut().collect();
+        elems.sort_by_key(|elem| elem.offset);
+
+        let mut vertex_count = 0;
+        let mut indices: Vec<u32> = vec![];
+        // removed objects may have been the only ones too big for u16
+        let mut segments = IndexSegments::new();
+        for elem in elems {
+            let old_base = (elem.offset / layout.stride()) as u32;
+            let new_base = vertex_count as u32;
+            let range = elem.index_offset..elem.index_offset + elem.index_count;
+            let elem_vertices = elem.local_vertex_data.len() / layout.floats();
+
+            elem.offset = vertex_count * layout.stride();
+            elem.index_offset = indices.len();
+            elem.segment = segments.add(vertex_count, elem_vertices).0;
+
+            indices.extend(
+                old_indices[range]
+                    .iter()
+                    .map(|index| index - old_base + new_base),
+            );
+            vertex_count += elem_vertices;
+        }
+
+        self.offset = vertex_count;
+        self.draw_ranges = None;
+        self.indices = Some(indices);
+
+        // no capacity makes upload_pending allocate the buffers again, nothing is copied from the
+        // old ones as nothing is uploaded
+        self.vertex_capacity = 0;
+        self.index_capacity = 0;
+        self.segments = segments;
+        self.uploaded_vertices = 0;
+        self.uploaded_indices = 0;
+        self.upload_pending(gl);
+    }
+
+    pub fn hitbox_as_ref(&self, handle: ObjectHandle) -> Result<&Box3D, String> {
+        Ok(&self.offset(handle)?.hitbox)
+    }
+
+    // Replaces the world hitbox until the object moves again, broadphase gets it too (collider
+    // stays as it is)
+    pub fn set_hitbox(&mut self, handle: ObjectHandle, hitbox: Box3D) -> Result<(), String> {
+        let elem = self.offset_mut(handle)?;
+        elem.hitbox = hitbox;
+        let proxy = elem.proxy;
+        self.broadphase.update(proxy, hitbox);
+        Ok(())
+    }
+
+    // Exact shape of the object, collider is given in local space (around the local mesh) and
+    // follows the object, None goes back to the hitbox
+    // Broadphase only knows the hitbox, so colliders sticking out of it can be missed by pairs
+    pub fn set_collider(
+        &mut self,
+        handle: ObjectHandle,
+        local_collider: Option<Collider>,
+    ) -> Result<(), String> {
+        let elem = self.offset_mut(handle)?;
+        elem.collider = local_collider
+            .as_ref()
+            .map(|collider| collider.transformed(elem.transform.matrix()));
+        elem.local_collider = local_collider;
+        Ok(())
+    }
+
+    // Oriented box around the local mesh as the collider, it is rotated with the object instead
+    // of growing like the hitbox, false goes back to the hitbox
+    pub fn set_obb_hitbox(&mut self, handle: ObjectHandle, enabled: bool) -> Result<(), String> {
+        let local_hitbox = self.offset(handle)?.local_hitbox;
+        self.set_collider(
+            handle,
+            enabled.then(|| Collider::Obb(Obb3D::from_box(&local_hitbox))),
+        )
+    }
+
+    // Convex hull of the full local mesh as the collider
+    pub fn set_hull_collider(&mut self, handle: ObjectHandle) -> Result<(), String> {
+        let elem = self.offset(handle)?;
+        // consumed objects set the layout
+        let layout = self.layout.as_ref().unwrap();
+        let hull = ConvexHull::from_vertex_data(layout, &elem.local_vertex_data);
+        self.set_collider(handle, Some(Collider::Hull(hull)))
+    }
+
+    pub fn obb(&self, handle: ObjectHandle) -> Result<Option<&Obb3D>, String> {
+        Ok(match &self.offset(handle)?.collider {
+            Some(Collider::Obb(obb)) => Some(obb),
+            _ => None,
+        })
+    }
+
+    // World collider of the object, its hitbox when it has none
+    pub fn collider(&self, handle: ObjectHandle) -> Result<Cow<'_, Collider>, String> {
+        let elem = self.offset(handle)?;
+        Ok(match &elem.collider {
+            Some(collider) => Cow::Borrowed(collider),
+            None => Cow::Owned(Collider::Aabb(elem.hitbox)),
+        })
+    }
+
+    // Exact test of two objects, colliders are used where objects have them
+    pub fn colide(&self, a: ObjectHandle, b: ObjectHandle) -> Result<bool, String> {
+        Ok(self.collider(a)?.intersects(&*self.collider(b)?))
+    }
+
+    // Same as colide against a box which is not an object
+    pub fn colide_box(&self, handle: ObjectHandle, r#box: &Box3D) -> Result<bool, String> {
+        Ok(self.collider(handle)?.intersects(&Collider::Aabb(*r#box)))
+    }
+
+    // Every pair of objects with overlapping hitboxes, each pair once
+    pub fn overlapping_pairs(&self) -> Vec<(ObjectHandle, ObjectHandle)> {
+        self.broadphase.overlapping_pairs()
+    }
+
+    // overlapping_pairs which also pass colide, so colliders really touch
+    pub fn colliding_pairs(&self) -> Vec<(ObjectHandle, ObjectHandle)> {
+        self.overlapping_pairs()
+            .into_iter()
+            .filter(|&(a, b)| self.colide(a, b).unwrap())
+            .collect()
+    }
+
+    // Every object with hitbox overlapping r#box
+    pub fn query_box(&self, r#box: &Box3D) -> Vec<ObjectHandle> {
+        self.broadphase.query_box(r#box)
+    }
+
+    // Every object with hitbox closer than radius to center
+    pub fn query_radius(&self, center: Vec3, radius: f32) -> Vec<ObjectHandle> {
+        self.broadphase.query_radius(center, radius)
+    }
+
+    // Nearest triangle of visible objects closer than max_distance, hitboxes are tested first
+    // Triangles of the full mesh (not the drawn level of detail) are tested in local space of
+    // the object, world vertices are not kept
+    pub fn raycast(&self, ray: &Ray, max_distance: f32) -> Option<RayHit> {
+        let layout = self.layout.as_ref()?;
+        let indices = self.indices.as_ref()?;
+        let (position, _) = layout.position_normal();
+        let floats = layout.floats();
+        let mut nearest: Option<RayHit> = None;
+
+        for (index, entry) in self.objects.iter().enumerate() {
+            let elem = match &entry.offset {
+                Some(elem) if elem.visible => elem,
+                _ => continue,
+            };
+            let limit = nearest.as_ref().map_or(max_distance, |hit| hit.distance);
+            match ray.intersect_box(&elem.hitbox) {
+                Some(distance) if distance <= limit => (),
+                _ => continue,
+            }
+
+            let model = elem.transform.matrix();
+            let inverse = model.inversed();
+            let local_ray = ray.transformed(inverse);
+            let base = elem.offset / layout.stride();
+            let vertex = |index: u32| {
+                let at = (index as usize - base) * floats + position;
+                Vec3::new(
+                    elem.local_vertex_data[at],
+                    elem.local_vertex_data[at + 1],
+                    elem.local_vertex_data[at + 2],
+                )
+            };
+            let full_mesh = &elem.lods[0];
+            let mut best: Option<(f32, Vec3)> = None;
+            for triangle in indices
+                [elem.index_offset + full_mesh.start..elem.index_offset + full_mesh.end]
+                .chunks_exact(3)
+            {
+                let (a, b, c) = (
+                    vertex(triangle[0]),
+                    vertex(triangle[1]),
+                    vertex(triangle[2]),
+                );
+                if let Some(distance) = local_ray.intersect_triangle(a, b, c)
+                    && distance <= best.map_or(limit, |(best, _)| best)
+                {
+                    best = Some((distance, (b - a).cross(c - a)));
                 }
             }
-            None => {
-                panic!("Shitfuck no name in map, function: change_position");
+
+            if let Some((distance, local_normal)) = best {
+                // normals go to world space through inverse transpose
+                let mut normal = Vec3::from(
+                    inverse.transposed()
+                        * Vec4::new(local_normal.x, local_normal.y, local_normal.z, 0.0),
+                )
+                .normalized();
+                if normal.dot(ray.direction) > 0.0 {
+                    normal = -normal;
+                }
+                nearest = Some(RayHit {
+                    handle: ObjectHandle {
+                        index: index as u32,
+                        generation: entry.generation,
+                    },
+                    name: elem.name.clone(),
+                    distance,
+                    point: ray.at(distance),
+                    normal,
+                });
             }
         }
+        nearest
     }
+}
 
-    pub fn hitbox_as_ref(&mut self, name: &str) -> Option<&Box3D>{
-        match self.offset_map.get_mut(name) {
-            Some(elem) => {
-                return Some(&elem.hitbox);
+/*
+    InstancedBatch:
+    0. one mesh uploaded once, drawn N times with DrawElementsInstanced
+    1. every instance has model matrix and tint in the instance buffer
+    2. needs shader/instanced_vert.glsl (instance attributes at locations 5-9, mesh layouts must
+       leave them free)
+*/
 
-            }
-            None => {
-                panic!("Fuck you!");
-            }
+// mat4 model + vec4 tint
+const INSTANCE_FLOATS: usize = 20;
+const INSTANCE_LEN: usize = size_of::<[f32; INSTANCE_FLOATS]>();
+const INSTANCE_MODEL_LOCATION: u32 = 5;
+const INSTANCE_TINT_LOCATION: u32 = 9;
+
+pub struct Instance {
+    name: String,
+    transform: Transform,
+    tint: Vec4,
+    hitbox: Box3D,
+}
+
+pub struct InstancedBatch {
+    mesh: BatchObject,
+    // u16 unless the mesh has too many vertices for it
+    index_type: IndexType,
+    instances: Vec<Instance>,
+    instance_map: HashMap<String, usize>,
+    dirty_instances: Option<Range<usize>>,
+    instance_capacity: usize,
+    instance_vbo: Option<helper::Buffer>,
+    ebo: Option<helper::Buffer>,
+    vbo: Option<helper::Buffer>,
+    vao: Option<helper::VertexArray>,
+    texture: Option<u32>,
+}
+
+// instances are placed relative to position of the mesh BatchObject, so an instance at
+// mesh.position is drawn exactly where the BatchObject is
+impl InstancedBatch {
+    // Locations of instance attributes are fixed by the shader, meshes using them (or not fitting
+    // the shader otherwise) are refused
+    pub fn new(mesh: BatchObject) -> Result<Self, String> {
+        mesh.layout.check_shader_inputs()?;
+        let instance_locations = INSTANCE_MODEL_LOCATION..=INSTANCE_TINT_LOCATION;
+        if let Some(attribute) = mesh
+            .layout
+            .attributes()
+            .iter()
+            .find(|attribute| instance_locations.contains(&attribute.location))
+        {
+            return Err(format!(
+                "{:?} of the mesh is at location {}, locations {}-{} are instance attributes",
+                attribute.semantic,
+                attribute.location,
+                INSTANCE_MODEL_LOCATION,
+                INSTANCE_TINT_LOCATION
+            ));
+        }
+        let index_type = if mesh.vertex_data.len() / mesh.layout.floats() <= U16_VERTICES {
+            IndexType::U16
+        } else {
+            IndexType::U32
+        };
+        Ok(Self {
+            mesh,
+            index_type,
+            instances: vec![],
+            instance_map: HashMap::new(),
+            dirty_instances: None,
+            instance_capacity: 0,
+            instance_vbo: None,
+            ebo: None,
+            vbo: None,
+            vao: None,
+            texture: None,
+        })
+    }
+
+    pub fn add_instance(
+        &mut self,
+        name: &str,
+        position: Vec3,
+        tint: Option<Vec4>,
+    ) -> Result<(), String> {
+        if self.instance_map.contains_key(name) {
+            return Err(format!("Name {} already taken in InstancedBatch", name));
         }
+        let transform = Transform::from_translation(position);
+        let hitbox = self
+            .mesh
+            .hitbox
+            .transformed(self.instance_model(&transform));
+
+        self.instance_map
+            .insert(name.to_string(), self.instances.len());
+        self.instances.push(Instance {
+            name: name.to_string(),
+            transform,
+            tint: tint.unwrap_or(Vec4::one()),
+            hitbox,
+        });
+        self.mark_dirty(self.instances.len() - 1);
+        Ok(())
     }
 
-    pub fn hitbox_as_mut(&mut self, name: &str) -> Option<&mut Box3D>{
-        match self.offset_map.get_mut(name) {
-            Some(elem) => {
-                return Some(&mut elem.hitbox);
+    // Last instance takes place of the removed one, so the instance buffer stays packed
+    pub fn remove_instance(&mut self, name: &str) -> Result<(), String> {
+        let (index, _) = self.instance_mut(name)?;
+        self.instance_map.remove(name);
+        self.instances.swap_remove(index);
+        if index < self.instances.len() {
+            self.instance_map
+                .insert(self.instances[index].name.clone(), index);
+            self.mark_dirty(index);
+        }
+        Ok(())
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.instances.len()
+    }
+
+    fn mark_dirty(&mut self, index: usize) {
+        self.dirty_instances = Some(match self.dirty_instances.take() {
+            Some(range) => range.start.min(index)..range.end.max(index + 1),
+            None => index..index + 1,
+        });
+    }
+
+    fn instance(&self, name: &str) -> Result<&Instance, String> {
+        match self.instance_map.get(name) {
+            Some(&index) => Ok(&self.instances[index]),
+            None => Err(format!("Shitfuck no name in map: {}", name)),
+        }
+    }
+
+    fn instance_mut(&mut self, name: &str) -> Result<(usize, &mut Instance), String> {
+        match self.instance_map.get(name) {
+            Some(&index) => Ok((index, &mut self.instances[index])),
+            None => Err(format!("Shitfuck no name in map: {}", name)),
+        }
+    }
+
+    pub fn send_data(&mut self, gl: Rc<GlFns>, texture_png_path: Option<&str>) {
+        let vao = helper::VertexArray::new(gl.clone()).expect("Couldn`t make a VAO");
+        vao.bind();
+
+        let ebo = helper::Buffer::new(gl.clone()).expect("Couldn't make a EBO");
+        ebo.bind(GL_ELEMENT_ARRAY_BUFFER);
+        helper::buffer_data(
+            gl.clone(),
+            GL_ELEMENT_ARRAY_BUFFER,
+            &encode_indices(&self.mesh.indices, 0, self.index_type),
+            GL_STATIC_DRAW,
+        );
+
+        let vbo = helper::Buffer::new(gl.clone()).expect("Couldn't make a VBO");
+        vbo.bind(GL_ARRAY_BUFFER);
+        helper::buffer_data(
+            gl.clone(),
+            GL_ARRAY_BUFFER,
+            bytemuck::cast_slice(&self.mesh.vertex_data),
+            GL_STATIC_DRAW,
+        );
+        self.mesh.layout.apply(&gl);
+
+        let instance_vbo = helper::Buffer::new(gl.clone()).expect("Couldn't make a VBO");
+        instance_vbo.bind(GL_ARRAY_BUFFER);
+        instance_attrib_pointers(&gl);
+
+        self.vao = Some(vao);
+        self.vbo = Some(vbo);
+        self.ebo = Some(ebo);
+        self.instance_vbo = Some(instance_vbo);
+        self.instance_capacity = 0;
+        self.dirty_instances = Some(0..self.instances.len());
+        self.upload_instances(gl.clone());
+
+        self.texture = Some(create_batch_texture(gl, texture_png_path));
+    }
+
+    // mesh vertices have mesh.position baked in, it is taken away before the transform
+    fn instance_model(&self, transform: &Transform) -> Mat4 {
+        transform.matrix() * Mat4::from_translation(-self.mesh.position)
+    }
+
+    fn instance_data(&self, instances: Range<usize>) -> Vec<f32> {
+        let mut data = Vec::with_capacity(instances.len() * INSTANCE_FLOATS);
+        for instance in &self.instances[instances] {
+            data.extend_from_slice(self.instance_model(&instance.transform).as_slice());
+            data.extend_from_slice(instance.tint.as_slice());
+        }
+        data
+    }
 
+    // Only changed instances are uploaded, the buffer grows geometrically like in DynamicBatch
+    pub fn upload_instances(&mut self, gl: Rc<GlFns>) {
+        let (instance_vbo, range) = match (&self.instance_vbo, self.dirty_instances.take()) {
+            (Some(instance_vbo), Some(range)) => (instance_vbo, range),
+            (_, range) => {
+                self.dirty_instances = range;
+                return;
             }
-            None => {
-                panic!("Fuck you!");
+        };
+        instance_vbo.bind(GL_ARRAY_BUFFER);
+
+        if self.instances.len() > self.instance_capacity {
+            self.instance_capacity = grown_capacity(self.instance_capacity, self.instances.len());
+            helper::allocate_buffer(
+                gl.clone(),
+                GL_ARRAY_BUFFER,
+                self.instance_capacity * INSTANCE_LEN,
+                GL_DYNAMIC_DRAW,
+            );
+            helper::buffer_sub_data(
+                gl,
+                GL_ARRAY_BUFFER,
+                0,
+                bytemuck::cast_slice(&self.instance_data(0..self.instances.len())),
+            );
+            return;
+        }
+
+        let range = range.start..range.end.min(self.instances.len());
+        if range.is_empty() {
+            return;
+        }
+        helper::buffer_sub_data(
+            gl,
+            GL_ARRAY_BUFFER,
+            range.start * INSTANCE_LEN,
+            bytemuck::cast_slice(&self.instance_data(range)),
+        );
+    }
+
+    pub fn draw(&mut self, gl: Rc<GlFns>) {
+        self.upload_instances(gl.clone());
+        match (&self.vao, self.texture) {
+            (Some(vao), Some(texture)) => unsafe {
+                helper::bind_texture(&gl, texture);
+                vao.bind();
+                gl.DrawElementsInstanced(
+                    GL_TRIANGLES,
+                    self.mesh.lods[0].len().try_into().unwrap(),
+                    self.index_type.gl_type(),
+                    (self.mesh.lods[0].start * self.index_type.size()) as *const _,
+                    self.instances.len().try_into().unwrap(),
+                );
+            },
+
+            _ => {
+                panic!("Shit happened!");
             }
         }
     }
+
+    pub fn move_delta(&mut self, name: &str, transformation: Mat4) -> Result<(), String> {
+        let instance = self.instance(name)?;
+        let transform = Transform::from_matrix(transformation * instance.transform.matrix());
+        self.apply_transform(name, transform)
+    }
+
+    pub fn change_position(&mut self, name: &str, position: Vec3) -> Result<(), String> {
+        let instance = self.instance(name)?;
+        let transform = Transform {
+            translation: position,
+            ..instance.transform
+        };
+        self.apply_transform(name, transform)
+    }
+
+    pub fn set_transform(
+        &mut self,
+        name: &str,
+        translation: Vec3,
+        rotation: Rotor3,
+        scale: Vec3,
+    ) -> Result<(), String> {
+        self.apply_transform(
+            name,
+            Transform {
+                translation,
+                rotation,
+                scale,
+            },
+        )
+    }
+
+    pub fn get_transform(&self, name: &str) -> Result<Transform, String> {
+        Ok(self.instance(name)?.transform)
+    }
+
+    fn apply_transform(&mut self, name: &str, transform: Transform) -> Result<(), String> {
+        let hitbox = self
+            .mesh
+            .hitbox
+            .transformed(self.instance_model(&transform));
+        let (index, instance) = self.instance_mut(name)?;
+        instance.transform = transform;
+        instance.hitbox = hitbox;
+        self.mark_dirty(index);
+        Ok(())
+    }
+
+    pub fn set_tint(&mut self, name: &str, tint: Vec4) -> Result<(), String> {
+        let (index, instance) = self.instance_mut(name)?;
+        instance.tint = tint;
+        self.mark_dirty(index);
+        Ok(())
+    }
+
+    pub fn hitbox_as_ref(&self, name: &str) -> Result<&Box3D, String> {
+        Ok(&self.instance(name)?.hitbox)
+    }
+
+    pub fn hitbox_as_mut(&mut self, name: &str) -> Result<&mut Box3D, String> {
+        Ok(&mut self.instance_mut(name)?.1.hitbox)
+    }
+}
+
+// Per instance attributes read from the buffer bound to GL_ARRAY_BUFFER, mat4 takes 4 locations
+fn instance_attrib_pointers(gl: &GlFns) {
+    unsafe {
+        for column in 0..4 {
+            let location = INSTANCE_MODEL_LOCATION + column;
+            gl.VertexAttribPointer(
+                location,
+                4,
+                GL_FLOAT,
+                0,
+                INSTANCE_LEN.try_into().unwrap(),
+                (column as usize * size_of::<[f32; 4]>()) as *const _,
+            );
+            gl.EnableVertexAttribArray(location);
+            gl.VertexAttribDivisor(location, 1);
+        }
+
+        gl.VertexAttribPointer(
+            INSTANCE_TINT_LOCATION,
+            4,
+            GL_FLOAT,
+            0,
+            INSTANCE_LEN.try_into().unwrap(),
+            size_of::<[f32; 16]>() as *const _,
+        );
+        gl.EnableVertexAttribArray(INSTANCE_TINT_LOCATION);
+        gl.VertexAttribDivisor(INSTANCE_TINT_LOCATION, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vertex::AttributeType;
+
+    #[test]
+    fn merge_ranges_glues_adjacent_ranges_with_same_base_vertex() {
+        let ranges = vec![
+            (0..3, 0),
+            (3..6, 0),
+            (6..9, 4),
+            (12..15, 4),
+            (15..15, 4),
+            (15..18, 4),
+        ];
+        assert_eq!(
+            merge_ranges(ranges.into_iter()),
+            vec![(0..6, 0), (6..9, 4), (12..18, 4)]
+        );
+    }
+
+    #[test]
+    fn merge_ranges_keeps_gaps_left_by_hidden_objects() {
+        let ranges = vec![(0..3, 0), (6..9, 0), (10..12, 0)];
+        assert_eq!(
+            merge_ranges(ranges.into_iter()),
+            vec![(0..3, 0), (6..9, 0), (10..12, 0)]
+        );
+    }
+
+    #[test]
+    fn draw_ranges_skip_hidden_and_culled_objects() {
+        let lods = [0..3, 3..6];
+        let inside = Box3D::new(Vec3::one(), -Vec3::one());
+        let outside = Box3D::new(Vec3::new(1.0, 1.0, -20.0), Vec3::new(-1.0, -1.0, -30.0));
+        let object = |index_offset, lod, hitbox, visible| DrawnObject {
+            index_offset,
+            lod: &lods[lod],
+            base_vertex: 0,
+            hitbox,
+            visible,
+        };
+        // given out of order, like DynamicBatch objects reusing freed places
+        let objects = || {
+            [
+                object(6, 0, &outside, true),
+                object(0, 0, &inside, true),
+                object(3, 1, &inside, false),
+                object(3, 0, &inside, true),
+            ]
+            .into_iter()
+        };
+        assert_eq!(build_draw_ranges(objects(), None), (vec![(0..9, 0)], 0));
+        let projection = ultraviolet::projection::perspective_gl(1.0, 1.0, 0.1, 10.0);
+        let frustum = Frustum::from_matrix(projection);
+        assert_eq!(
+            build_draw_ranges(objects(), Some(&frustum)),
+            (vec![(0..6, 0)], 1)
+        );
+    }
+
+    #[test]
+    fn lod_select_walks_thresholds() {
+        let settings = LodSettings::new(vec![10.0, 20.0]).with_hysteresis(1.0);
+        assert_eq!(settings.select(0, 5.0, 3), 0);
+        assert_eq!(settings.select(0, 25.0, 3), 2);
+        // inside hysteresis the last level stays
+        assert_eq!(settings.select(1, 9.5, 3), 1);
+        assert_eq!(settings.select(0, 10.5, 3), 0);
+        // never past the levels the object has
+        assert_eq!(settings.select(0, 25.0, 2), 1);
+    }
+
+    fn mesh(layout: VertexLayout) -> BatchObject {
+        BatchObject {
+            vertex_data: vec![0.0; layout.floats() * 3],
+            indices: vec![0, 1, 2],
+            layout,
+            position: Vec3::zero(),
+            hitbox: Box3D::new(Vec3::one(), Vec3::zero()),
+            parts: vec![],
+            lods: vec![Range { start: 0, end: 3 }],
+        }
+    }
+
+    #[test]
+    fn instanced_batch_refuses_layouts_at_instance_locations() {
+        assert!(InstancedBatch::new(mesh(VertexLayout::standard())).is_ok());
+        let layout = VertexLayout::standard().with(
+            Semantic::TexCoord(1),
+            INSTANCE_MODEL_LOCATION + 1,
+            2,
+            AttributeType::Float,
+            false,
+        );
+        assert!(InstancedBatch::new(mesh(layout)).is_err());
+    }
+
+    #[test]
+    fn instanced_batch_names_give_errors() {
+        let mut batch = InstancedBatch::new(mesh(VertexLayout::standard())).unwrap();
+        batch.add_instance("a", Vec3::zero(), None).unwrap();
+        batch.add_instance("b", Vec3::one(), None).unwrap();
+        assert!(batch.add_instance("a", Vec3::one(), None).is_err());
+        assert_eq!(
+            batch.set_tint("c", Vec4::one()),
+            Err("Shitfuck no name in map: c".to_string())
+        );
+        batch.remove_instance("a").unwrap();
+        assert!(batch.remove_instance("a").is_err());
+        assert!(batch.change_position("a", Vec3::one()).is_err());
+        // b took place of a in the instance buffer
+        batch.change_position("b", Vec3::zero()).unwrap();
+        assert_eq!(batch.get_transform("b").unwrap().translation, Vec3::zero());
+        assert_eq!(batch.instance_count(), 1);
+    }
+
+    // position, normal and uv only, with a tangent after the shader inputs
+    fn custom_layout() -> VertexLayout {
+        VertexLayout::new()
+            .with(Semantic::Position, 0, 3, AttributeType::Float, false)
+            .with(Semantic::Normal, 1, 3, AttributeType::Float, false)
+            .with(Semantic::TexCoord(0), 2, 2, AttributeType::Float, false)
+            .with(Semantic::Tangent, 5, 4, AttributeType::Float, false)
+    }
+
+    #[test]
+    fn batches_take_layout_without_colour_and_object_index() {
+        let mut static_batch = StaticBatch::new();
+        static_batch.consume_object(mesh(custom_layout()));
+        static_batch.consume_object(mesh(custom_layout()));
+        let mut dynamic_batch = DynamicBatch::new();
+        let handle = dynamic_batch.consume_object(mesh(custom_layout()));
+        dynamic_batch
+            .set_transform(handle, Vec3::one(), Rotor3::identity(), Vec3::one())
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit the shader")]
+    fn batches_refuse_layout_not_fitting_shader() {
+        let layout = custom_layout().with(Semantic::Color, 6, 3, AttributeType::Float, false);
+        StaticBatch::new().consume_object(mesh(layout));
+    }
+
+    #[test]
+    fn select_lods_reports_changes_only() {
+        let settings = Some(LodSettings::new(vec![10.0]));
+        let near = Box3D::new(Vec3::one(), Vec3::zero());
+        let far = Box3D::new(Vec3::new(21.0, 1.0, 1.0), Vec3::new(20.0, 0.0, 0.0));
+        let mut lods = [0, 0];
+        let mut select = |settings: &Option<LodSettings>, camera_position: Vec3| {
+            let [near_lod, far_lod] = &mut lods;
+            let objects = [(&near, 2, near_lod), (&far, 2, far_lod)];
+            select_lods(settings, objects.into_iter(), camera_position)
+        };
+        assert!(select(&settings, Vec3::zero()));
+        assert!(!select(&settings, Vec3::zero()));
+        assert!(!select(&None, Vec3::one() * 100.0));
+        assert_eq!(lods, [0, 1]);
+        apply_lod_settings(&mut None, None, lods.iter_mut());
+        assert_eq!(lods, [0, 0]);
+    }
+
+    #[test]
+    fn lod_select_clamps_current_to_fewer_thresholds() {
+        let settings = LodSettings::new(vec![10.0]);
+        assert_eq!(settings.select(3, 5.0, 4), 0);
+        assert_eq!(settings.select(3, 50.0, 4), 1);
+        assert_eq!(LodSettings::new(vec![]).select(2, 50.0, 4), 0);
+    }
 }
@@ -0,0 +1,354 @@
+#![allow(unused, dead_code)]
+use crate::collision::Box3D;
+use ultraviolet::Vec3;
+
+/*
+    AabbTree (dynamic bounding volume tree):
+    0. every leaf is one item with its hitbox, inner nodes have box around both children
+    1. leaves keep a fat box (hitbox grown by FAT_MARGIN), moving inside it changes nothing in
+       the tree, moving out of it reinserts the leaf
+    2. new leaf goes next to the node which makes the tree grow least (surface area heuristic)
+    3. queries skip whole subtrees whose box misses, leaves are tested with the exact hitbox
+*/
+
+const NULL: usize = usize::MAX;
+// small moves (an object wobbling every frame) stay inside the fat box
+const FAT_MARGIN: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProxyId(usize);
+
+struct Node<T> {
+    // exact hitbox of leaves, inner nodes do not use it
+    hitbox: Box3D,
+    fat: Box3D,
+    parent: usize,
+    left: usize,
+    right: usize,
+    // Some only in leaves
+    item: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn is_leaf(&self) -> bool {
+        self.left == NULL
+    }
+}
+
+pub struct AabbTree<T> {
+    nodes: Vec<Node<T>>,
+    free_nodes: Vec<usize>,
+    root: usize,
+    leaf_count: usize,
+}
+
+impl<T: Copy> AabbTree<T> {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![],
+            free_nodes: vec![],
+            root: NULL,
+            leaf_count: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaf_count
+    }
+
+    fn allocate(&mut self, node: Node<T>) -> usize {
+        match self.free_nodes.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn free(&mut self, index: usize) {
+        self.nodes[index].item = None;
+        self.free_nodes.push(index);
+    }
+
+    pub fn insert(&mut self, hitbox: Box3D, item: T) -> ProxyId {
+        let leaf = self.allocate(Node {
+            hitbox,
+            fat: hitbox.grown(FAT_MARGIN),
+            parent: NULL,
+            left: NULL,
+            right: NULL,
+            item: Some(item),
+        });
+        self.insert_leaf(leaf);
+        self.leaf_count += 1;
+        ProxyId(leaf)
+    }
+
+    fn leaf(&self, proxy: ProxyId) -> &Node<T> {
+        match self.nodes.get(proxy.0) {
+            Some(node) if node.item.is_some() => node,
+            _ => {
+                panic!("No proxy {:?} in AabbTree!", proxy);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, proxy: ProxyId) {
+        self.leaf(proxy);
+        self.remove_leaf(proxy.0);
+        self.free(proxy.0);
+        self.leaf_count -= 1;
+    }
+
+    // Returns true when the leaf had to be reinserted
+    pub fn update(&mut self, proxy: ProxyId, hitbox: Box3D) -> bool {
+        self.leaf(proxy);
+        let node = &mut self.nodes[proxy.0];
+        node.hitbox = hitbox;
+        if node.fat.contains(&hitbox) {
+            return false;
+        }
+        node.fat = hitbox.grown(FAT_MARGIN);
+        self.remove_leaf(proxy.0);
+        self.insert_leaf(proxy.0);
+        true
+    }
+
+    pub fn hitbox(&self, proxy: ProxyId) -> &Box3D {
+        &self.leaf(proxy).hitbox
+    }
+
+    pub fn item(&self, proxy: ProxyId) -> T {
+        self.leaf(proxy).item.unwrap()
+    }
+
+    // Cost of putting the leaf under node, area of the new parent plus growth of node
+    fn insertion_cost(&self, node: usize, fat: &Box3D, inherited: f32) -> f32 {
+        let node = &self.nodes[node];
+        let area = node.fat.union(fat).surface_area();
+        if node.is_leaf() {
+            area + inherited
+        } else {
+            area - node.fat.surface_area() + inherited
+        }
+    }
+
+    fn insert_leaf(&mut self, leaf: usize) {
+        if self.root == NULL {
+            self.root = leaf;
+            self.nodes[leaf].parent = NULL;
+            return;
+        }
+
+        // walking down to the cheapest sibling, stops where a new parent here is cheaper than
+        // going into either child
+        let fat = self.nodes[leaf].fat;
+        let mut index = self.root;
+        while !self.nodes[index].is_leaf() {
+            let node = &self.nodes[index];
+            let area = node.fat.surface_area();
+            let combined = node.fat.union(&fat).surface_area();
+            let cost = 2.0 * combined;
+            let inherited = 2.0 * (combined - area);
+            let left_cost = self.insertion_cost(node.left, &fat, inherited);
+            let right_cost = self.insertion_cost(node.right, &fat, inherited);
+            if cost < left_cost && cost < right_cost {
+                break;
+            }
+            index = if left_cost < right_cost {
+                node.left
+            } else {
+                node.right
+            };
+        }
+
+        let sibling = index;
+        let old_parent = self.nodes[sibling].parent;
+        let parent = self.allocate(Node {
+            hitbox: fat,
+            fat: self.nodes[sibling].fat.union(&fat),
+            parent: old_parent,
+            left: sibling,
+            right: leaf,
+            item: None,
+        });
+        self.nodes[sibling].parent = parent;
+        self.nodes[leaf].parent = parent;
+        if old_parent == NULL {
+            self.root = parent;
+        } else if self.nodes[old_parent].left == sibling {
+            self.nodes[old_parent].left = parent;
+        } else {
+            self.nodes[old_parent].right = parent;
+        }
+        self.refit(old_parent);
+    }
+
+    fn remove_leaf(&mut self, leaf: usize) {
+        if leaf == self.root {
+            self.root = NULL;
+            return;
+        }
+        let parent = self.nodes[leaf].parent;
+        let grandparent = self.nodes[parent].parent;
+        let sibling = if self.nodes[parent].left == leaf {
+            self.nodes[parent].right
+        } else {
+            self.nodes[parent].left
+        };
+
+        // sibling takes place of the parent
+        self.nodes[sibling].parent = grandparent;
+        if grandparent == NULL {
+            self.root = sibling;
+        } else if self.nodes[grandparent].left == parent {
+            self.nodes[grandparent].left = sibling;
+        } else {
+            self.nodes[grandparent].right = sibling;
+        }
+        self.free(parent);
+        self.refit(grandparent);
+    }
+
+    // Boxes of index and every node above it are made from their children again
+    fn refit(&mut self, mut index: usize) {
+        while index != NULL {
+            let node = &self.nodes[index];
+            let fat = self.nodes[node.left].fat.union(&self.nodes[node.right].fat);
+            self.nodes[index].fat = fat;
+            index = self.nodes[index].parent;
+        }
+    }
+
+    // Calls found for every leaf whose fat box passes enters and whose hitbox passes accepts,
+    // enters has to pass for every box around an accepted hitbox
+    fn query(
+        &self,
+        enters: impl Fn(&Box3D) -> bool,
+        accepts: impl Fn(&Box3D) -> bool,
+        mut found: impl FnMut(usize),
+    ) {
+        if self.root == NULL {
+            return;
+        }
+        let mut stack = vec![self.root];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !enters(&node.fat) {
+                continue;
+            }
+            if node.is_leaf() {
+                if accepts(&node.hitbox) {
+                    found(index);
+                }
+            } else {
+                stack.push(node.left);
+                stack.push(node.right);
+            }
+        }
+    }
+
+    // Every item with hitbox overlapping r#box
+    pub fn query_box(&self, r#box: &Box3D) -> Vec<T> {
+        let mut items = vec![];
+        self.query(
+            |fat| fat.colide(r#box),
+            |hitbox| hitbox.colide(r#box),
+            |leaf| items.push(self.nodes[leaf].item.unwrap()),
+        );
+        items
+    }
+
+    // Every item with hitbox closer than radius to center
+    pub fn query_radius(&self, center: Vec3, radius: f32) -> Vec<T> {
+        let mut items = vec![];
+        self.query(
+            |fat| fat.distance(center) <= radius,
+            |hitbox| hitbox.distance(center) <= radius,
+            |leaf| items.push(self.nodes[leaf].item.unwrap()),
+        );
+        items
+    }
+
+    // Every pair of items with overlapping hitboxes, each pair once
+    pub fn overlapping_pairs(&self) -> Vec<(T, T)> {
+        let mut pairs = vec![];
+        for (leaf, node) in self.nodes.iter().enumerate() {
+            let item = match node.item {
+                Some(item) => item,
+                None => continue,
+            };
+            let hitbox = node.hitbox;
+            self.query(
+                |fat| fat.colide(&hitbox),
+                |other| other.colide(&hitbox),
+                |other| {
+                    // the pair is found from both leaves, the lower one keeps it
+                    if other > leaf {
+                        pairs.push((item, self.nodes[other].item.unwrap()));
+                    }
+                },
+            );
+        }
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube(center: Vec3) -> Box3D {
+        Box3D::new(center + Vec3::one() * 0.5, center - Vec3::one() * 0.5)
+    }
+
+    fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+        items.sort();
+        items
+    }
+
+    #[test]
+    fn insert_move_remove() {
+        let mut tree = AabbTree::new();
+        let proxies: Vec<ProxyId> = (0..10)
+            .map(|item| tree.insert(cube(Vec3::new(item as f32 * 2.0, 0.0, 0.0)), item))
+            .collect();
+        assert_eq!(tree.len(), 10);
+        assert_eq!(
+            sorted(tree.query_box(&cube(Vec3::new(4.0, 0.0, 0.0)))),
+            vec![2]
+        );
+        assert_eq!(
+            sorted(tree.query_radius(Vec3::new(5.0, 0.0, 0.0), 0.6)),
+            vec![2, 3]
+        );
+        assert!(tree.overlapping_pairs().is_empty());
+
+        // inside the fat box nothing is reinserted, but queries see the new hitbox
+        assert!(!tree.update(proxies[3], cube(Vec3::new(6.05, 0.0, 0.0))));
+        assert_eq!(tree.query_box(&cube(Vec3::new(6.05, 0.0, 0.0))), vec![3]);
+        assert!(tree.update(proxies[3], cube(Vec3::new(4.5, 0.0, 0.0))));
+        assert_eq!(
+            sorted(tree.query_box(&cube(Vec3::new(4.0, 0.0, 0.0)))),
+            vec![2, 3]
+        );
+        assert_eq!(tree.overlapping_pairs().len(), 1);
+
+        tree.remove(proxies[2]);
+        assert_eq!(tree.len(), 9);
+        assert_eq!(tree.query_box(&cube(Vec3::new(4.0, 0.0, 0.0))), vec![3]);
+        assert!(tree.overlapping_pairs().is_empty());
+
+        // freed node is used again
+        let proxy = tree.insert(cube(Vec3::new(4.0, 0.0, 0.0)), 20);
+        assert_eq!(tree.item(proxy), 20);
+        assert_eq!(
+            sorted(tree.query_box(&cube(Vec3::new(4.0, 0.0, 0.0)))),
+            vec![3, 20]
+        );
+    }
+}
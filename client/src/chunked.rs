@@ -0,0 +1,206 @@
+#![allow(unused, dead_code)]
+use crate::batching::{BatchObject, LodSettings, StaticBatch, StaticObjectId};
+use crate::collision::{Box3D, Frustum};
+use crate::helper::create_batch_texture;
+use gl33::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+use ultraviolet::Vec3;
+
+/*
+    ChunkedStaticBatch:
+    0. world is split into a grid of chunk_size cubes, every chunk is its own StaticBatch
+    1. object goes to the chunk its hitbox center is in, bounds of the chunk grow around its
+       objects (so they can stick out of the cell)
+    2. whole chunks are culled with the frustum or released and uploaded again by distance
+*/
+
+pub type ChunkKey = (i32, i32, i32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkedObjectId {
+    chunk: ChunkKey,
+    id: StaticObjectId,
+}
+
+struct Chunk {
+    batch: StaticBatch,
+    bounds: Box3D,
+}
+
+pub struct ChunkedStaticBatch {
+    chunk_size: f32,
+    chunks: HashMap<ChunkKey, Chunk>,
+    // shared by every chunk, made by send_data
+    texture: Option<u32>,
+    // counted like culled objects of StaticBatch, only in whole chunks
+    culled: usize,
+    // given to every chunk, also to the ones made later
+    lod_settings: Option<LodSettings>,
+}
+
+impl ChunkedStaticBatch {
+    pub fn new(chunk_size: f32) -> Self {
+        if chunk_size <= 0.0 {
+            panic!("Chunk size has to be positive!");
+        }
+        Self {
+            chunk_size,
+            chunks: HashMap::new(),
+            texture: None,
+            culled: 0,
+            lod_settings: None,
+        }
+    }
+
+    pub fn chunk_key(&self, point: Vec3) -> ChunkKey {
+        (
+            (point.x / self.chunk_size).floor() as i32,
+            (point.y / self.chunk_size).floor() as i32,
+            (point.z / self.chunk_size).floor() as i32,
+        )
+    }
+
+    // Objects consumed after send_data are uploaded once their chunk is loaded again
+    pub fn consume_object(&mut self, object: BatchObject) -> ChunkedObjectId {
+        let key = self.chunk_key(object.hitbox.center());
+        let hitbox = object.hitbox;
+        let lod_settings = &self.lod_settings;
+        let chunk = self.chunks.entry(key).or_insert_with(|| {
+            let mut batch = StaticBatch::new();
+            batch.set_lod_settings(lod_settings.clone());
+            Chunk {
+                batch,
+                bounds: hitbox,
+            }
+        });
+        chunk.bounds = chunk.bounds.union(&hitbox);
+        // uploaded buffers would miss the new object
+        chunk.batch.release();
+
+        ChunkedObjectId {
+            chunk: key,
+            id: chunk.batch.consume_object(object),
+        }
+    }
+
+    fn chunk_mut(&mut self, key: ChunkKey) -> &mut Chunk {
+        match self.chunks.get_mut(&key) {
+            Some(chunk) => chunk,
+            None => {
+                panic!("No chunk {:?} in ChunkedStaticBatch!", key);
+            }
+        }
+    }
+
+    pub fn set_visible(&mut self, id: ChunkedObjectId, visible: bool) {
+        self.chunk_mut(id.chunk).batch.set_visible(id.id, visible);
+    }
+
+    pub fn is_visible(&self, id: ChunkedObjectId) -> bool {
+        self.chunks
+            .get(&id.chunk)
+            .is_some_and(|chunk| chunk.batch.is_visible(id.id))
+    }
+
+    pub fn set_lod_settings(&mut self, lod_settings: Option<LodSettings>) {
+        for chunk in self.chunks.values_mut() {
+            chunk.batch.set_lod_settings(lod_settings.clone());
+        }
+        self.lod_settings = lod_settings;
+    }
+
+    // only loaded chunks are updated, released ones catch up once they are loaded and updated
+    pub fn update_lods(&mut self, camera_position: Vec3) {
+        for chunk in self.chunks.values_mut() {
+            if chunk.batch.is_sent() {
+                chunk.batch.update_lods(camera_position);
+            }
+        }
+    }
+
+    pub fn send_data(&mut self, gl: Rc<GlFns>, texture_png_path: Option<&str>) {
+        let texture = create_batch_texture(gl.clone(), texture_png_path);
+        self.texture = Some(texture);
+        for chunk in self.chunks.values_mut() {
+            chunk.batch.send_data_with_texture(gl.clone(), texture);
+        }
+    }
+
+    pub fn load_chunk(&mut self, gl: Rc<GlFns>, key: ChunkKey) {
+        let texture = match self.texture {
+            Some(texture) => texture,
+            None => {
+                panic!("send_data has to be called before chunks are loaded!");
+            }
+        };
+        let chunk = self.chunk_mut(key);
+        if !chunk.batch.is_sent() {
+            chunk.batch.send_data_with_texture(gl, texture);
+        }
+    }
+
+    pub fn release_chunk(&mut self, key: ChunkKey) {
+        self.chunk_mut(key).batch.release();
+    }
+
+    // Chunks with bounds closer than radius to center are on the GPU, the rest is released
+    pub fn stream(&mut self, gl: Rc<GlFns>, center: Vec3, radius: f32) {
+        let keys: Vec<ChunkKey> = self.chunks.keys().copied().collect();
+        for key in keys {
+            if self.chunks[&key].bounds.distance(center) <= radius {
+                self.load_chunk(gl.clone(), key);
+            } else {
+                self.release_chunk(key);
+            }
+        }
+    }
+
+    // released chunks are skipped
+    pub fn draw(&mut self, gl: Rc<GlFns>) {
+        self.culled = 0;
+        for chunk in self.chunks.values_mut() {
+            if chunk.batch.is_sent() {
+                chunk.batch.draw(gl.clone());
+            }
+        }
+    }
+
+    // Same as draw, chunks with bounds outside the frustum are skipped and counted in culled
+    pub fn draw_culled(&mut self, gl: Rc<GlFns>, frustum: &Frustum) {
+        self.culled = 0;
+        for chunk in self.chunks.values_mut() {
+            if !chunk.batch.is_sent() {
+                continue;
+            }
+            if !frustum.intersects(&chunk.bounds) {
+                self.culled += 1;
+                continue;
+            }
+            chunk.batch.draw(gl.clone());
+        }
+    }
+
+    pub fn culled(&self) -> usize {
+        self.culled
+    }
+
+    pub fn chunk_bounds(&self, key: ChunkKey) -> Option<&Box3D> {
+        self.chunks.get(&key).map(|chunk| &chunk.bounds)
+    }
+
+    pub fn chunk_keys(&self) -> impl Iterator<Item = ChunkKey> + '_ {
+        self.chunks.keys().copied()
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn loaded_chunk_count(&self) -> usize {
+        self.chunks
+            .values()
+            .filter(|chunk| chunk.batch.is_sent())
+            .count()
+    }
+}
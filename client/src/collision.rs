@@ -1,4 +1,7 @@
-use ultraviolet::{Mat4, Vec3, Vec4};
+#![allow(unused, dead_code)]
+use crate::hull::convex_hull;
+use crate::vertex::VertexLayout;
+use ultraviolet::{Mat3, Mat4, Rotor3, Vec3, Vec4};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Box3D {
@@ -24,7 +27,33 @@ impl Box3D {
             && self.min_vertex.z <= r#box.max_vertex.z
     }
 
+    // Smallest move of self which ends the overlap with r#box, None when they only touch or are
+    // apart
+    pub fn penetration(&self, r#box: &Box3D) -> Option<Vec3> {
+        let mut best: Option<Vec3> = None;
+        for axis in 0..3 {
+            // pushing self towards min or towards max of r#box
+            let to_min = r#box.min_vertex[axis] - self.max_vertex[axis];
+            let to_max = r#box.max_vertex[axis] - self.min_vertex[axis];
+            if to_min >= 0.0 || to_max <= 0.0 {
+                return None;
+            }
+            let push = if -to_min < to_max { to_min } else { to_max };
+            if best.is_none_or(|best| push.abs() < best.mag()) {
+                let mut vector = Vec3::zero();
+                vector[axis] = push;
+                best = Some(vector);
+            }
+        }
+        best
+    }
+
     pub fn transformation(&mut self, mat: Mat4) {
+        *self = self.transformed(mat);
+    }
+
+    // Smallest box around all 8 transformed corners
+    pub fn transformed(&self, mat: Mat4) -> Box3D {
         let corners = [
             Vec3::new(self.min_vertex.x, self.min_vertex.y, self.min_vertex.z),
             Vec3::new(self.min_vertex.x, self.min_vertex.y, self.max_vertex.z),
@@ -40,16 +69,77 @@ impl Box3D {
         let mut max_vertex = Vec3::new(f32::MIN, f32::MIN, f32::MIN);
 
         for c in &corners {
-            let t = mat * Vec4::new(c.x, c.y, c.z, 1.0); // w = 1
-
-            self.min_vertex.x = min_vertex.x.min(t.x);
-            self.min_vertex.y = min_vertex.y.min(t.y);
-            self.min_vertex.z = min_vertex.z.min(t.z);
+            let t = Vec3::from(mat * Vec4::new(c.x, c.y, c.z, 1.0)); // w = 1
 
-            self.max_vertex.x = max_vertex.x.max(t.x);
-            self.max_vertex.y = max_vertex.y.max(t.y);
-            self.max_vertex.z = max_vertex.z.max(t.z);
+            min_vertex = min_vertex.min_by_component(t);
+            max_vertex = max_vertex.max_by_component(t);
         }
+
+        Box3D::new(max_vertex, min_vertex)
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.max_vertex - self.min_vertex
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min_vertex + self.max_vertex) * 0.5
+    }
+
+    // Smallest box around both boxes
+    pub fn union(&self, r#box: &Box3D) -> Box3D {
+        Box3D::new(
+            self.max_vertex.max_by_component(r#box.max_vertex),
+            self.min_vertex.min_by_component(r#box.min_vertex),
+        )
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size = self.max_vertex - self.min_vertex;
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    // True when the whole box is inside self
+    pub fn contains(&self, r#box: &Box3D) -> bool {
+        self.min_vertex.x <= r#box.min_vertex.x
+            && self.min_vertex.y <= r#box.min_vertex.y
+            && self.min_vertex.z <= r#box.min_vertex.z
+            && self.max_vertex.x >= r#box.max_vertex.x
+            && self.max_vertex.y >= r#box.max_vertex.y
+            && self.max_vertex.z >= r#box.max_vertex.z
+    }
+
+    // Box bigger by margin on every side
+    pub fn grown(&self, margin: f32) -> Box3D {
+        let margin = Vec3::broadcast(margin);
+        Box3D::new(self.max_vertex + margin, self.min_vertex - margin)
+    }
+
+    // Distance from the point to the closest point of the box, 0 inside
+    pub fn distance(&self, point: Vec3) -> f32 {
+        let closest = point.clamped(self.min_vertex, self.max_vertex);
+        (point - closest).mag()
+    }
+
+    // Corner furthest in direction
+    pub fn support(&self, direction: Vec3) -> Vec3 {
+        Vec3::new(
+            if direction.x >= 0.0 {
+                self.max_vertex.x
+            } else {
+                self.min_vertex.x
+            },
+            if direction.y >= 0.0 {
+                self.max_vertex.y
+            } else {
+                self.min_vertex.y
+            },
+            if direction.z >= 0.0 {
+                self.max_vertex.z
+            } else {
+                self.min_vertex.z
+            },
+        )
     }
 
     #[inline]
@@ -66,3 +156,801 @@ impl Box3D {
     }
     */
 }
+
+// keeps SAT from finding a false separating axis when two edges are (almost) parallel and their
+// cross product is close to zero
+const SAT_EPSILON: f32 = 1e-6;
+
+// Box rotated around its center, columns of rotation are its local axes (orthonormal)
+#[derive(Debug, Clone, Copy)]
+pub struct Obb3D {
+    pub center: Vec3,
+    pub half_extents: Vec3,
+    pub rotation: Mat3,
+}
+
+impl Obb3D {
+    pub fn new(center: Vec3, half_extents: Vec3, rotation: Rotor3) -> Obb3D {
+        Self {
+            center,
+            half_extents,
+            rotation: rotation.into_matrix(),
+        }
+    }
+
+    // Same space as the Box3D, not rotated
+    pub fn from_box(r#box: &Box3D) -> Obb3D {
+        Self {
+            center: r#box.center(),
+            half_extents: r#box.size() * 0.5,
+            rotation: Mat3::identity(),
+        }
+    }
+
+    // mat has to keep the axes perpendicular (translation, rotation and scale, scale before
+    // rotation), scale goes into half_extents so the box stays exact
+    pub fn transformed(&self, mat: Mat4) -> Obb3D {
+        let linear = mat.truncate();
+        let mut half_extents = Vec3::zero();
+        let mut rotation = Mat3::identity();
+        for axis in 0..3 {
+            let column = linear * self.rotation.cols[axis];
+            half_extents[axis] = self.half_extents[axis] * column.mag();
+            rotation.cols[axis] = column.normalized();
+        }
+        Self {
+            center: mat.transform_point3(self.center),
+            half_extents,
+            rotation,
+        }
+    }
+
+    // Smallest Box3D around the box
+    pub fn bounding_box(&self) -> Box3D {
+        let mut extent = Vec3::zero();
+        for axis in 0..3 {
+            extent += self.rotation.cols[axis].abs() * self.half_extents[axis];
+        }
+        Box3D::new(self.center + extent, self.center - extent)
+    }
+
+    // Corner furthest in direction
+    pub fn support(&self, direction: Vec3) -> Vec3 {
+        let mut corner = self.center;
+        for axis in 0..3 {
+            let column = self.rotation.cols[axis] * self.half_extents[axis];
+            if column.dot(direction) >= 0.0 {
+                corner += column;
+            } else {
+                corner -= column;
+            }
+        }
+        corner
+    }
+
+    // Separating axis test over the 3 + 3 face normals and 9 edge cross products, touching
+    // boxes intersect
+    pub fn intersects(&self, other: &Obb3D) -> bool {
+        let a = &self.rotation.cols;
+        let b = &other.rotation.cols;
+        let (ea, eb) = (self.half_extents, other.half_extents);
+
+        // other in the frame of self
+        let mut r = [[0.0; 3]; 3];
+        let mut abs_r = [[0.0; 3]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                r[i][j] = a[i].dot(b[j]);
+                abs_r[i][j] = r[i][j].abs() + SAT_EPSILON;
+            }
+        }
+        let d = other.center - self.center;
+        let t = [d.dot(a[0]), d.dot(a[1]), d.dot(a[2])];
+
+        for i in 0..3 {
+            let rb = eb[0] * abs_r[i][0] + eb[1] * abs_r[i][1] + eb[2] * abs_r[i][2];
+            if t[i].abs() > ea[i] + rb {
+                return false;
+            }
+        }
+        for j in 0..3 {
+            let ra = ea[0] * abs_r[0][j] + ea[1] * abs_r[1][j] + ea[2] * abs_r[2][j];
+            let distance = t[0] * r[0][j] + t[1] * r[1][j] + t[2] * r[2][j];
+            if distance.abs() > ra + eb[j] {
+                return false;
+            }
+        }
+        for i in 0..3 {
+            let (i1, i2) = ((i + 1) % 3, (i + 2) % 3);
+            for j in 0..3 {
+                let (j1, j2) = ((j + 1) % 3, (j + 2) % 3);
+                let ra = ea[i1] * abs_r[i2][j] + ea[i2] * abs_r[i1][j];
+                let rb = eb[j1] * abs_r[i][j2] + eb[j2] * abs_r[i][j1];
+                let distance = t[i2] * r[i1][j] - t[i1] * r[i2][j];
+                if distance.abs() > ra + rb {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    pub fn intersects_box(&self, r#box: &Box3D) -> bool {
+        self.intersects(&Obb3D::from_box(r#box))
+    }
+}
+
+// Half-line from origin, distances along it are in lengths of direction
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+}
+
+impl Ray {
+    // direction is normalized, so distances are in world units
+    pub fn new(origin: Vec3, direction: Vec3) -> Ray {
+        Self {
+            origin,
+            direction: direction.normalized(),
+        }
+    }
+
+    // Ray through a pixel of the screen (y goes down), from the near plane to the far one
+    pub fn from_screen(
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        projection: Mat4,
+        view: Mat4,
+    ) -> Ray {
+        let inverse = (projection * view).inversed();
+        let ndc_x = 2.0 * x / width - 1.0;
+        let ndc_y = 1.0 - 2.0 * y / height;
+        let unproject = |z: f32| {
+            let point = inverse * Vec4::new(ndc_x, ndc_y, z, 1.0);
+            point.xyz() / point.w
+        };
+        let near = unproject(-1.0);
+        Ray::new(near, unproject(1.0) - near)
+    }
+
+    pub fn at(&self, distance: f32) -> Vec3 {
+        self.origin + self.direction * distance
+    }
+
+    // Ray in the space mat takes points to, distances stay the same
+    pub fn transformed(&self, mat: Mat4) -> Ray {
+        Self {
+            origin: Vec3::from(mat * Vec4::new(self.origin.x, self.origin.y, self.origin.z, 1.0)),
+            direction: Vec3::from(
+                mat * Vec4::new(self.direction.x, self.direction.y, self.direction.z, 0.0),
+            ),
+        }
+    }
+
+    // Slab test, distance where the ray enters the box (0 when origin is inside)
+    pub fn intersect_box(&self, r#box: &Box3D) -> Option<f32> {
+        let inverse = Vec3::one() / self.direction;
+        let t1 = (r#box.min_vertex - self.origin) * inverse;
+        let t2 = (r#box.max_vertex - self.origin) * inverse;
+        let near = t1.min_by_component(t2).component_max().max(0.0);
+        let far = t1.max_by_component(t2).component_min();
+        if far >= near { Some(near) } else { None }
+    }
+
+    // Moller-Trumbore, both sides of the triangle are hit
+    pub fn intersect_triangle(&self, a: Vec3, b: Vec3, c: Vec3) -> Option<f32> {
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = self.direction.cross(edge2);
+        let determinant = edge1.dot(p);
+        if determinant.abs() < f32::EPSILON {
+            // ray is parallel to the triangle
+            return None;
+        }
+        let inverse = 1.0 / determinant;
+        let s = self.origin - a;
+        let u = s.dot(p) * inverse;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = s.cross(edge1);
+        let v = self.direction.dot(q) * inverse;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let distance = edge2.dot(q) * inverse;
+        if distance >= 0.0 {
+            Some(distance)
+        } else {
+            None
+        }
+    }
+}
+
+// Six planes (normal xyz, distance w) pointing inside: left, right, bottom, top, near, far
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    // Planes of the clip space box taken from rows of projection * view (Gribb/Hartmann), with
+    // model in the matrix the planes are in model space
+    pub fn from_matrix(mat: Mat4) -> Frustum {
+        let row = |i: usize| {
+            Vec4::new(
+                mat.cols[0][i],
+                mat.cols[1][i],
+                mat.cols[2][i],
+                mat.cols[3][i],
+            )
+        };
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let mut planes = [w + x, w - x, w + y, w - y, w + z, w - z];
+        for plane in &mut planes {
+            *plane /= plane.xyz().mag();
+        }
+        Self { planes }
+    }
+
+    // Box is outside when its corner furthest along the normal is behind any plane, boxes near
+    // frustum corners can pass even if they are outside
+    pub fn intersects(&self, r#box: &Box3D) -> bool {
+        self.planes.iter().all(|plane| {
+            let corner = Vec3::new(
+                if plane.x >= 0.0 {
+                    r#box.max_vertex.x
+                } else {
+                    r#box.min_vertex.x
+                },
+                if plane.y >= 0.0 {
+                    r#box.max_vertex.y
+                } else {
+                    r#box.min_vertex.y
+                },
+                if plane.z >= 0.0 {
+                    r#box.max_vertex.z
+                } else {
+                    r#box.min_vertex.z
+                },
+            );
+            plane.xyz().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f32) -> Sphere {
+        Self { center, radius }
+    }
+
+    pub fn bounding_box(&self) -> Box3D {
+        let radius = Vec3::broadcast(self.radius);
+        Box3D::new(self.center + radius, self.center - radius)
+    }
+}
+
+// Every point closer than radius to the segment from start to end
+#[derive(Debug, Clone, Copy)]
+pub struct Capsule {
+    pub start: Vec3,
+    pub end: Vec3,
+    pub radius: f32,
+}
+
+impl Capsule {
+    pub fn new(start: Vec3, end: Vec3, radius: f32) -> Capsule {
+        Self { start, end, radius }
+    }
+
+    // Point of the segment closest to point
+    pub fn closest_point(&self, point: Vec3) -> Vec3 {
+        let segment = self.end - self.start;
+        let length_sq = segment.mag_sq();
+        if length_sq == 0.0 {
+            return self.start;
+        }
+        let t = ((point - self.start).dot(segment) / length_sq).clamp(0.0, 1.0);
+        self.start + segment * t
+    }
+
+    pub fn bounding_box(&self) -> Box3D {
+        let radius = Vec3::broadcast(self.radius);
+        Box3D::new(
+            self.start.max_by_component(self.end) + radius,
+            self.start.min_by_component(self.end) - radius,
+        )
+    }
+
+    pub fn move_delta(&mut self, delta: Vec3) {
+        self.start += delta;
+        self.end += delta;
+    }
+}
+
+// Only vertices of the hull are kept, they are enough for GJK
+#[derive(Debug, Clone)]
+pub struct ConvexHull {
+    pub points: Vec<Vec3>,
+}
+
+impl ConvexHull {
+    pub fn from_points(points: &[Vec3]) -> ConvexHull {
+        if points.is_empty() {
+            panic!("ConvexHull needs at least one point!");
+        }
+        Self {
+            points: convex_hull(points),
+        }
+    }
+
+    // Hull around positions of every vertex
+    pub fn from_vertex_data(layout: &VertexLayout, vertex_data: &[f32]) -> ConvexHull {
+        let (position, _) = layout.position_normal();
+        let points: Vec<Vec3> = vertex_data
+            .chunks_exact(layout.floats())
+            .map(|vertex| Vec3::new(vertex[position], vertex[position + 1], vertex[position + 2]))
+            .collect();
+        Self::from_points(&points)
+    }
+
+    pub fn bounding_box(&self) -> Box3D {
+        let (min, max) = self
+            .points
+            .iter()
+            .fold((self.points[0], self.points[0]), |(min, max), point| {
+                (min.min_by_component(*point), max.max_by_component(*point))
+            });
+        Box3D::new(max, min)
+    }
+
+    pub fn support(&self, direction: Vec3) -> Vec3 {
+        *self
+            .points
+            .iter()
+            .max_by(|a, b| a.dot(direction).total_cmp(&b.dot(direction)))
+            .unwrap()
+    }
+}
+
+// Exact shape of something for collisions, Box3D hitboxes are only the first (broadphase) test
+#[derive(Debug, Clone)]
+pub enum Collider {
+    Aabb(Box3D),
+    Obb(Obb3D),
+    Sphere(Sphere),
+    Capsule(Capsule),
+    Hull(ConvexHull),
+}
+
+// Longest axis of mat after scale, radii grow by it
+fn max_scale(mat: Mat4) -> f32 {
+    (0..3)
+        .map(|axis| mat.cols[axis].xyz().mag())
+        .fold(0.0, f32::max)
+}
+
+impl Collider {
+    pub fn bounding_box(&self) -> Box3D {
+        match self {
+            Collider::Aabb(r#box) => *r#box,
+            Collider::Obb(obb) => obb.bounding_box(),
+            Collider::Sphere(sphere) => sphere.bounding_box(),
+            Collider::Capsule(capsule) => capsule.bounding_box(),
+            Collider::Hull(hull) => hull.bounding_box(),
+        }
+    }
+
+    // Aabb grows around the rotated box, spheres and capsules stay round (radius is scaled by the
+    // longest axis)
+    pub fn transformed(&self, mat: Mat4) -> Collider {
+        match self {
+            Collider::Aabb(r#box) => Collider::Aabb(r#box.transformed(mat)),
+            Collider::Obb(obb) => Collider::Obb(obb.transformed(mat)),
+            Collider::Sphere(sphere) => Collider::Sphere(Sphere::new(
+                mat.transform_point3(sphere.center),
+                sphere.radius * max_scale(mat),
+            )),
+            Collider::Capsule(capsule) => Collider::Capsule(Capsule::new(
+                mat.transform_point3(capsule.start),
+                mat.transform_point3(capsule.end),
+                capsule.radius * max_scale(mat),
+            )),
+            Collider::Hull(hull) => Collider::Hull(ConvexHull {
+                points: hull
+                    .points
+                    .iter()
+                    .map(|point| mat.transform_point3(*point))
+                    .collect(),
+            }),
+        }
+    }
+
+    // Point of the inner shape furthest in direction, spheres and capsules are only their center
+    // and segment here, radius() is added around them
+    fn support(&self, direction: Vec3) -> Vec3 {
+        match self {
+            Collider::Aabb(r#box) => r#box.support(direction),
+            Collider::Obb(obb) => obb.support(direction),
+            Collider::Sphere(sphere) => sphere.center,
+            Collider::Capsule(capsule) => {
+                if (capsule.end - capsule.start).dot(direction) >= 0.0 {
+                    capsule.end
+                } else {
+                    capsule.start
+                }
+            }
+            Collider::Hull(hull) => hull.support(direction),
+        }
+    }
+
+    fn radius(&self) -> f32 {
+        match self {
+            Collider::Sphere(sphere) => sphere.radius,
+            Collider::Capsule(capsule) => capsule.radius,
+            _ => 0.0,
+        }
+    }
+
+    // Touching shapes intersect, pairs without a simple test go through GJK
+    pub fn intersects(&self, other: &Collider) -> bool {
+        match (self, other) {
+            (Collider::Aabb(a), Collider::Aabb(b)) => a.colide(b),
+            (Collider::Obb(a), Collider::Obb(b)) => a.intersects(b),
+            (Collider::Obb(obb), Collider::Aabb(r#box))
+            | (Collider::Aabb(r#box), Collider::Obb(obb)) => obb.intersects_box(r#box),
+            (Collider::Sphere(a), Collider::Sphere(b)) => {
+                (a.center - b.center).mag() <= a.radius + b.radius
+            }
+            (Collider::Sphere(sphere), Collider::Aabb(r#box))
+            | (Collider::Aabb(r#box), Collider::Sphere(sphere)) => {
+                r#box.distance(sphere.center) <= sphere.radius
+            }
+            (Collider::Sphere(sphere), Collider::Capsule(capsule))
+            | (Collider::Capsule(capsule), Collider::Sphere(sphere)) => {
+                (capsule.closest_point(sphere.center) - sphere.center).mag()
+                    <= sphere.radius + capsule.radius
+            }
+            _ => self.distance(other) <= 0.0,
+        }
+    }
+
+    // Gap between the surfaces, 0 when they overlap
+    pub fn distance(&self, other: &Collider) -> f32 {
+        match closest_points(self, other) {
+            Some((a, b)) => ((a - b).mag() - self.radius() - other.radius()).max(0.0),
+            None => 0.0,
+        }
+    }
+
+    // Smallest move of self which ends the overlap with other, None when they only touch or are
+    // apart
+    // Exact while the inner shapes are apart (segment of a capsule outside of a box), deeper
+    // overlaps are pushed out of the bounding box of other
+    pub fn penetration(&self, other: &Collider) -> Option<Vec3> {
+        match closest_points(self, other) {
+            Some((a, b)) => {
+                let between = a - b;
+                let distance = between.mag();
+                let depth = self.radius() + other.radius() - distance;
+                if depth > 0.0 {
+                    Some(between / distance * depth)
+                } else {
+                    None
+                }
+            }
+            None => self.bounding_box().penetration(&other.bounding_box()),
+        }
+    }
+}
+
+/*
+    GJK (distance):
+    0. a - b (Minkowski difference) of two convex shapes holds the origin only when they overlap,
+       its point closest to the origin is the shortest vector between them
+    1. simplex (1-4 points of a - b, each from support of a and b in opposite directions) is
+       reduced to the feature closest to the origin, closest point of it is the next direction
+    2. when support in that direction gets no closer, the closest point is found, when the
+       simplex holds the origin the shapes overlap
+*/
+
+const GJK_ITERATIONS: usize = 64;
+// relative improvement of the distance below which GJK stops
+const GJK_TOLERANCE: f32 = 1e-6;
+// part of the size of a - b, closer to the origin is only rounding (long shapes going through
+// each other can get stuck a bit off it) and the shapes touch
+const GJK_TOUCH: f32 = 1e-5;
+// triangles (tetrahedra) with area (volume) below this part of their edges multiplied are flat
+const GJK_FLAT: f32 = 1e-5;
+
+#[derive(Debug, Clone, Copy)]
+struct SupportPoint {
+    // a - b
+    point: Vec3,
+    a: Vec3,
+    b: Vec3,
+}
+
+// Points of the simplex closest to the origin with their barycentric weights
+type Simplex = (Vec<SupportPoint>, Vec<f32>);
+
+fn weighted((points, weights): &Simplex, part: impl Fn(&SupportPoint) -> Vec3) -> Vec3 {
+    points
+        .iter()
+        .zip(weights)
+        .fold(Vec3::zero(), |sum, (point, weight)| {
+            sum + part(point) * *weight
+        })
+}
+
+// Closest points of the inner shapes of a and b, None when they overlap or touch
+fn closest_points(a: &Collider, b: &Collider) -> Option<(Vec3, Vec3)> {
+    let support = |direction: Vec3| {
+        let (a, b) = (a.support(direction), b.support(-direction));
+        SupportPoint { point: a - b, a, b }
+    };
+    let first = support(Vec3::unit_x());
+    let mut simplex: Simplex = (vec![first], vec![1.0]);
+    let mut closest = first.point;
+    let mut size_sq = first.point.mag_sq();
+    let touch_sq = |size_sq: f32| GJK_TOUCH * GJK_TOUCH * size_sq;
+    for _ in 0..GJK_ITERATIONS {
+        let distance_sq = closest.mag_sq();
+        if distance_sq <= touch_sq(size_sq) {
+            return None;
+        }
+        let next = support(-closest);
+        size_sq = size_sq.max(next.point.mag_sq());
+        // nothing of a - b is further towards the origin than closest
+        if distance_sq - closest.dot(next.point) <= GJK_TOLERANCE * distance_sq {
+            break;
+        }
+        let mut points = simplex.0.clone();
+        points.push(next);
+        let reduced = closest_on_simplex(&points)?;
+        let point = weighted(&reduced, |support| support.point);
+        // rounding can keep it from getting closer, closest is as good as it gets then
+        if point.mag_sq() >= distance_sq {
+            break;
+        }
+        simplex = reduced;
+        closest = point;
+    }
+    // distance is only trusted with a plane between the origin and a - b, rounding can stop GJK
+    // close to the origin while it is still inside
+    let separation = closest.dot(support(-closest).point) / closest.mag();
+    if separation <= GJK_TOUCH * size_sq.sqrt() {
+        return None;
+    }
+    Some((
+        weighted(&simplex, |support| support.a),
+        weighted(&simplex, |support| support.b),
+    ))
+}
+
+// None when the origin is inside (a tetrahedron)
+fn closest_on_simplex(points: &[SupportPoint]) -> Option<Simplex> {
+    match *points {
+        [a] => Some((vec![a], vec![1.0])),
+        [a, b] => Some(closest_on_segment(a, b)),
+        [a, b, c] => Some(closest_on_triangle(a, b, c)),
+        [a, b, c, d] => closest_on_tetrahedron(a, b, c, d),
+        _ => unreachable!(),
+    }
+}
+
+fn closest_on_segment(a: SupportPoint, b: SupportPoint) -> Simplex {
+    let segment = b.point - a.point;
+    let length_sq = segment.mag_sq();
+    let t = if length_sq > 0.0 {
+        -a.point.dot(segment) / length_sq
+    } else {
+        0.0
+    };
+    if t <= 0.0 {
+        (vec![a], vec![1.0])
+    } else if t >= 1.0 {
+        (vec![b], vec![1.0])
+    } else {
+        (vec![a, b], vec![1.0 - t, t])
+    }
+}
+
+// Voronoi regions of the triangle (Ericson, Real-Time Collision Detection 5.1.5)
+fn closest_on_triangle(a: SupportPoint, b: SupportPoint, c: SupportPoint) -> Simplex {
+    let ab = b.point - a.point;
+    let ac = c.point - a.point;
+    let (d1, d2) = (ab.dot(-a.point), ac.dot(-a.point));
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (vec![a], vec![1.0]);
+    }
+    let (d3, d4) = (ab.dot(-b.point), ac.dot(-b.point));
+    if d3 >= 0.0 && d4 <= d3 {
+        return (vec![b], vec![1.0]);
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return closest_on_segment(a, b);
+    }
+    let (d5, d6) = (ab.dot(-c.point), ac.dot(-c.point));
+    if d6 >= 0.0 && d5 <= d6 {
+        return (vec![c], vec![1.0]);
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return closest_on_segment(a, c);
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return closest_on_segment(b, c);
+    }
+    let sum = va + vb + vc;
+    let normal = ab.cross(ac);
+    if sum <= 0.0 || normal.mag() <= GJK_FLAT * ab.mag() * ac.mag() {
+        // flat triangle, closest of its edges
+        return [
+            closest_on_segment(a, b),
+            closest_on_segment(b, c),
+            closest_on_segment(a, c),
+        ]
+        .into_iter()
+        .min_by(|x, y| {
+            let x = weighted(x, |support| support.point).mag_sq();
+            let y = weighted(y, |support| support.point).mag_sq();
+            x.total_cmp(&y)
+        })
+        .unwrap();
+    }
+    // weights from the origin projected on the plane, vb / sum and vc / sum lose too much to
+    // rounding on long thin triangles (and GJK goes the wrong way)
+    let normal_sq = normal.mag_sq();
+    let point = normal * (a.point.dot(normal) / normal_sq);
+    let v = (c.point - point).cross(a.point - point).dot(normal) / normal_sq;
+    let w = (a.point - point).cross(b.point - point).dot(normal) / normal_sq;
+    (vec![a, b, c], vec![1.0 - v - w, v, w])
+}
+
+// Closest of the faces the origin is in front of
+fn closest_on_tetrahedron(
+    a: SupportPoint,
+    b: SupportPoint,
+    c: SupportPoint,
+    d: SupportPoint,
+) -> Option<Simplex> {
+    let (ab, ac, ad) = (b.point - a.point, c.point - a.point, d.point - a.point);
+    // flat tetrahedra have no inside (sign of their volume is only rounding), all of their faces
+    // are tried
+    let flat = ab.cross(ac).dot(ad).abs() <= GJK_FLAT * ab.mag() * ac.mag() * ad.mag();
+    let faces = [(a, b, c, d), (a, c, d, b), (a, d, b, c), (b, d, c, a)];
+    let mut best: Option<(Simplex, f32)> = None;
+    for (p, q, r, opposite) in faces {
+        let normal = (q.point - p.point).cross(r.point - p.point);
+        if !flat && normal.dot(-p.point) * normal.dot(opposite.point - p.point) > 0.0 {
+            continue;
+        }
+        let simplex = closest_on_triangle(p, q, r);
+        let distance_sq = weighted(&simplex, |support| support.point).mag_sq();
+        if best
+            .as_ref()
+            .is_none_or(|(_, best_distance_sq)| distance_sq < *best_distance_sq)
+        {
+            best = Some((simplex, distance_sq));
+        }
+    }
+    best.map(|(simplex, _)| simplex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> Box3D {
+        Box3D::new(Vec3::one(), -Vec3::one())
+    }
+
+    #[test]
+    fn penetration_pushes_self_out_along_shallowest_axis() {
+        let r#box = Box3D::new(Vec3::new(2.5, 1.0, 1.0), Vec3::new(0.5, -1.0, -1.0));
+        assert_eq!(
+            r#box.penetration(&unit_box()),
+            Some(Vec3::new(0.5, 0.0, 0.0))
+        );
+        assert_eq!(
+            unit_box().penetration(&r#box),
+            Some(Vec3::new(-0.5, 0.0, 0.0))
+        );
+    }
+
+    #[test]
+    fn penetration_is_none_for_touching_boxes() {
+        let touching = Box3D::new(Vec3::new(3.0, 1.0, 1.0), Vec3::new(1.0, -1.0, -1.0));
+        assert!(touching.colide(&unit_box()));
+        assert_eq!(touching.penetration(&unit_box()), None);
+    }
+
+    #[test]
+    fn obb_separated_while_bounding_boxes_overlap() {
+        let rotated = Obb3D::from_box(&unit_box()).transformed(
+            Mat4::from_translation(Vec3::new(2.2, 2.2, 0.0))
+                * Mat4::from_rotation_z(0.25 * std::f32::consts::PI),
+        );
+        assert!(rotated.bounding_box().colide(&unit_box()));
+        assert!(!rotated.intersects(&Obb3D::from_box(&unit_box())));
+        assert!(!rotated.intersects_box(&unit_box()));
+    }
+
+    #[test]
+    fn obb_touching_faces_intersect() {
+        let moved = Obb3D::from_box(&unit_box())
+            .transformed(Mat4::from_translation(Vec3::new(2.0, 0.0, 0.0)));
+        assert!(moved.intersects(&Obb3D::from_box(&unit_box())));
+        assert!(moved.intersects_box(&unit_box()));
+    }
+
+    #[test]
+    fn capsule_penetration_pushes_out_of_box() {
+        let capsule = Collider::Capsule(Capsule::new(
+            Vec3::new(2.5, -5.0, 0.0),
+            Vec3::new(2.5, 5.0, 0.0),
+            2.0,
+        ));
+        let penetration = capsule.penetration(&Collider::Aabb(unit_box())).unwrap();
+        assert!((penetration - Vec3::new(0.5, 0.0, 0.0)).mag() < 1e-4);
+    }
+
+    #[test]
+    fn gjk_sphere_to_hull_distance() {
+        let corners: Vec<Vec3> = (0..8)
+            .map(|corner| {
+                Vec3::new(
+                    if corner & 1 == 0 { -1.0 } else { 1.0 },
+                    if corner & 2 == 0 { -1.0 } else { 1.0 },
+                    if corner & 4 == 0 { -1.0 } else { 1.0 },
+                )
+            })
+            .collect();
+        let hull = Collider::Hull(ConvexHull::from_points(&corners));
+
+        let apart = Collider::Sphere(Sphere::new(Vec3::new(3.0, 0.0, 0.0), 1.0));
+        assert!((apart.distance(&hull) - 1.0).abs() < 1e-4);
+        assert!(!apart.intersects(&hull));
+
+        let corner = Collider::Sphere(Sphere::new(Vec3::new(2.0, 2.0, 2.0), 0.5));
+        assert!((corner.distance(&hull) - (3.0f32.sqrt() - 0.5)).abs() < 1e-4);
+
+        let overlapping = Collider::Sphere(Sphere::new(Vec3::new(1.5, 0.0, 0.0), 1.0));
+        assert_eq!(overlapping.distance(&hull), 0.0);
+        assert!(overlapping.intersects(&hull));
+    }
+
+    #[test]
+    fn gjk_finds_deep_overlap_of_aligned_capsule_and_box() {
+        let capsule = Collider::Capsule(Capsule::new(
+            Vec3::new(0.0, -97.9, 6.0),
+            Vec3::new(0.0, 97.9, 6.0),
+            2.1,
+        ));
+        // centered box used to stop GJK a bit off the origin
+        for x in [0.0, -0.5, 0.3, 0.9] {
+            let r#box = Collider::Aabb(Box3D::new(
+                Vec3::new(x + 1.0, 12.0, 7.0),
+                Vec3::new(x - 1.0, 3.0, 5.0),
+            ));
+            assert_eq!(capsule.distance(&r#box), 0.0);
+            assert!(capsule.intersects(&r#box));
+            // segment of the capsule goes through the box, it is pushed out of the bounding box
+            let penetration = capsule.penetration(&r#box).unwrap();
+            assert!((penetration.x.abs() - (3.1 - x.abs())).abs() < 1e-4);
+            assert!(penetration.y.abs() < 1e-4 && penetration.z.abs() < 1e-4);
+        }
+    }
+}
@@ -8,7 +8,7 @@ use std::rc::Rc;
 use ultraviolet::{Mat4, Vec3};
 use video::GlWindow;
 
-use crate::collision::Box3D;
+use crate::collision::{Box3D, Capsule, Collider, Ray};
 
 pub struct GlFnsWin {
     pub fns: Rc<GlFns>,
@@ -55,6 +55,10 @@ impl GlFnsWin {
         uniform_mat4fv(&self.fns, uniform_location, mat4);
     }
 
+    pub fn uniform_1i(&self, uniform_location: i32, value: i32) {
+        uniform_1i(&self.fns, uniform_location, value);
+    }
+
     pub fn clear(&self, mask: GLbitfield) {
         clear_gl_bitfield(&self.fns, mask);
     }
@@ -149,6 +153,74 @@ pub fn buffer_data(gl: Rc<GlFns>, ty: GLenum, data: &[u8], usage: GLenum) {
     }
 }
 
+// Allocates size bytes without filling them, contents are written later with buffer_sub_data
+pub fn allocate_buffer(gl: Rc<GlFns>, ty: GLenum, size: usize, usage: GLenum) {
+    unsafe {
+        gl.BufferData(ty, size.try_into().unwrap(), std::ptr::null(), usage);
+    }
+}
+
+pub fn buffer_sub_data(gl: Rc<GlFns>, ty: GLenum, offset: usize, data: &[u8]) {
+    unsafe {
+        gl.BufferSubData(
+            ty,
+            offset.try_into().unwrap(),
+            data.len().try_into().unwrap(),
+            data.as_ptr().cast(),
+        );
+    }
+}
+
+// GPU side copy of the first size bytes, used when a buffer has to grow
+pub fn copy_buffer(gl: Rc<GlFns>, from: &Buffer, to: &Buffer, size: usize) {
+    from.bind(GL_COPY_READ_BUFFER);
+    to.bind(GL_COPY_WRITE_BUFFER);
+    unsafe {
+        gl.CopyBufferSubData(
+            GL_COPY_READ_BUFFER,
+            GL_COPY_WRITE_BUFFER,
+            0,
+            0,
+            size.try_into().unwrap(),
+        );
+    }
+}
+
+// Draws index ranges of the bound element buffer with one call, every range with its own base
+// vertex, index_size is the size of index_type in bytes
+pub fn draw_index_ranges(
+    gl: &GlFns,
+    ranges: &[(std::ops::Range<usize>, usize)],
+    index_type: GLenum,
+    index_size: usize,
+) {
+    if ranges.is_empty() {
+        return;
+    }
+    let counts: Vec<i32> = ranges
+        .iter()
+        .map(|(range, _)| range.len().try_into().unwrap())
+        .collect();
+    let offsets: Vec<*const std::ffi::c_void> = ranges
+        .iter()
+        .map(|(range, _)| (range.start * index_size) as *const _)
+        .collect();
+    let base_vertices: Vec<i32> = ranges
+        .iter()
+        .map(|(_, base_vertex)| (*base_vertex).try_into().unwrap())
+        .collect();
+    unsafe {
+        gl.MultiDrawElementsBaseVertex(
+            GL_TRIANGLES,
+            counts.as_ptr(),
+            index_type,
+            offsets.as_ptr(),
+            ranges.len().try_into().unwrap(),
+            base_vertices.as_ptr(),
+        );
+    }
+}
+
 #[derive(Clone)]
 pub struct Shader(pub u32, Rc<GlFns>);
 impl Shader {
@@ -334,6 +406,29 @@ pub fn create_bitmap_from_png(path: &str) -> imagine::Bitmap {
 //Hehe not dropped
 pub fn create_texture(gl: Rc<GlFns>, path: &str) -> u32 {
     let bitmap = create_bitmap_from_png(path);
+    create_texture_from_pixels(
+        gl,
+        bitmap.width.try_into().unwrap(),
+        bitmap.height.try_into().unwrap(),
+        bytemuck::cast_slice(&bitmap.pixels),
+    )
+}
+
+// 1x1 white texture for untextured models, sampling it leaves vertex colours as they are
+pub fn create_white_texture(gl: Rc<GlFns>) -> u32 {
+    create_texture_from_pixels(gl, 1, 1, &[255, 255, 255, 255])
+}
+
+// Texture every batch is drawn with, without png the batch shows its vertex (material) colours
+// only
+pub fn create_batch_texture(gl: Rc<GlFns>, texture_png_path: Option<&str>) -> u32 {
+    match texture_png_path {
+        Some(path) => create_texture(gl, path),
+        None => create_white_texture(gl),
+    }
+}
+
+pub fn create_texture_from_pixels(gl: Rc<GlFns>, width: i32, height: i32, pixels: &[u8]) -> u32 {
     let mut texture = 0;
     unsafe {
         gl.GenTextures(1, &mut texture);
@@ -347,16 +442,51 @@ pub fn create_texture(gl: Rc<GlFns>, path: &str) -> u32 {
             GL_TEXTURE_2D,
             0,
             GL_RGBA.0 as i32,
-            bitmap.width.try_into().unwrap(),
-            bitmap.height.try_into().unwrap(),
+            width,
+            height,
             0,
             GL_RGBA,
             GL_UNSIGNED_BYTE,
-            bitmap.pixels.as_ptr().cast(),
+            pixels.as_ptr().cast(),
         );
         gl.GenerateMipmap(GL_TEXTURE_2D);
     }
-    return texture;
+    texture
+}
+
+#[inline]
+pub fn bind_texture(gl: &GlFns, texture: u32) {
+    unsafe {
+        gl.ActiveTexture(GL_TEXTURE0);
+        gl.BindTexture(GL_TEXTURE_2D, texture);
+    }
+}
+
+// Texture buffer view of a buffer with mat4 columns stored as RGBA32F texels
+pub fn create_buffer_texture(gl: Rc<GlFns>, buffer: &Buffer) -> u32 {
+    let mut texture = 0;
+    unsafe {
+        gl.GenTextures(1, &mut texture);
+    }
+    attach_buffer_texture(&gl, texture, buffer);
+    texture
+}
+
+#[inline]
+pub fn attach_buffer_texture(gl: &GlFns, texture: u32, buffer: &Buffer) {
+    unsafe {
+        gl.BindTexture(GL_TEXTURE_BUFFER, texture);
+        gl.TexBuffer(GL_TEXTURE_BUFFER, GL_RGBA32F, buffer.0);
+    }
+}
+
+#[inline]
+pub fn bind_buffer_texture(gl: &GlFns, texture: u32, unit: u32) {
+    unsafe {
+        gl.ActiveTexture(GLenum(GL_TEXTURE0.0 + unit));
+        gl.BindTexture(GL_TEXTURE_BUFFER, texture);
+        gl.ActiveTexture(GL_TEXTURE0);
+    }
 }
 
 #[inline]
@@ -384,6 +514,13 @@ pub fn get_uniform_location(gl: &GlFns, shader_program: &ShaderProgram, name: &s
     unsafe { gl.GetUniformLocation(shader_program.0, uniform_name.as_ptr().cast()) }
 }
 
+#[inline]
+pub fn uniform_1i(gl: &GlFns, uniform_location: i32, value: i32) {
+    unsafe {
+        gl.Uniform1i(uniform_location, value);
+    }
+}
+
 #[inline]
 pub fn uniform_mat4fv(gl: &GlFns, uniform_location: i32, mat4: Mat4) {
     unsafe {
@@ -403,7 +540,7 @@ pub struct EulerFPSCamera {
     pub position: Vec3,
     pitch_deg: f32,
     yaw_deg: f32,
-    pub hitbox: Box3D,
+    pub capsule: Capsule,
 }
 impl EulerFPSCamera {
     const UP: Vec3 = Vec3 {
@@ -411,6 +548,8 @@ impl EulerFPSCamera {
         y: 1.0,
         z: 0.0,
     };
+    // pushes out of overlapping colliders per step of update_position_colliding
+    const RESOLVE_ITERATIONS: usize = 4;
 
     fn make_front(&self) -> Vec3 {
         let pitch_rad = f32::to_radians(self.pitch_deg);
@@ -437,7 +576,8 @@ impl EulerFPSCamera {
         self.yaw_deg = (self.yaw_deg + d_yaw_deg) % 360.0;
     }
 
-    pub fn update_position(&mut self, keys: &HashSet<SDL_Keycode>, distance: f32) {
+    // Normalized direction picked by held WASD keys, zero when nothing moves
+    fn move_direction(&self, keys: &HashSet<SDL_Keycode>) -> Vec3 {
         let forward = self.make_front_perpendicular();
 
         // #[cfg(debug_assertions)]
@@ -445,7 +585,7 @@ impl EulerFPSCamera {
 
         let cross_normalized = forward.cross(Self::UP).normalized();
 
-        let mut move_vector = keys.iter().copied().fold(
+        let move_vector = keys.iter().copied().fold(
             Vec3 {
                 x: 0.0,
                 y: 0.0,
@@ -459,13 +599,67 @@ impl EulerFPSCamera {
                 _ => vec,
             },
         );
-        if !(move_vector.x == 0.0 && move_vector.y == 0.0 && move_vector.z == 0.0) {
-            move_vector = move_vector.normalized();
-            self.hitbox.move_delta(move_vector*distance);
-            self.position += move_vector * distance;
+        if move_vector.x == 0.0 && move_vector.y == 0.0 && move_vector.z == 0.0 {
+            move_vector
+        } else {
+            move_vector.normalized()
         }
     }
 
+    fn translate(&mut self, delta: Vec3) {
+        self.capsule.move_delta(delta);
+        self.position += delta;
+    }
+
+    pub fn update_position(&mut self, keys: &HashSet<SDL_Keycode>, distance: f32) {
+        let move_vector = self.move_direction(keys);
+        self.translate(move_vector * distance);
+    }
+
+    // Box around the capsule, for finding colliders near the camera
+    pub fn hitbox(&self) -> Box3D {
+        self.capsule.bounding_box()
+    }
+
+    // Same as update_position, but the capsule is pushed out of colliders after the move, so the
+    // camera slides along walls (only the part of the move into the wall is taken away) and
+    // stops in corners
+    // Long moves are split into steps shorter than half of the radius, so thin walls are not
+    // skipped
+    pub fn update_position_colliding(
+        &mut self,
+        keys: &HashSet<SDL_Keycode>,
+        distance: f32,
+        colliders: &[Collider],
+    ) {
+        let move_vector = self.move_direction(keys);
+        if distance <= 0.0 || move_vector.mag_sq() == 0.0 {
+            return;
+        }
+        let max_step = 0.5 * self.capsule.radius;
+        let steps = (distance / max_step).ceil().max(1.0) as usize;
+        for _ in 0..steps {
+            self.translate(move_vector * (distance / steps as f32));
+            // deepest overlap first, pushing out of it can end the others too
+            for _ in 0..Self::RESOLVE_ITERATIONS {
+                let body = Collider::Capsule(self.capsule);
+                let deepest = colliders
+                    .iter()
+                    .filter_map(|collider| body.penetration(collider))
+                    .max_by(|a, b| a.mag_sq().total_cmp(&b.mag_sq()));
+                match deepest {
+                    Some(push) => self.translate(push),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    // Ray from the camera through the middle of the screen
+    pub fn crosshair_ray(&self) -> Ray {
+        Ray::new(self.position, self.make_front())
+    }
+
     #[inline]
     pub fn make_view_matrix(&self) -> Mat4 {
         Mat4::look_at(self.position, self.position + self.make_front(), Self::UP)
@@ -473,25 +667,18 @@ impl EulerFPSCamera {
 
     #[inline]
     pub fn at_position(position: Vec3) -> Self {
-        let hitbox = Box3D::new(
-            position
-                + Vec3 {
-                    x: 2.1,
-                    y: 100.01,
-                    z: 2.1,
-                },
-            position
-                - Vec3 {
-                    x: 2.1,
-                    y: 100.01,
-                    z: 2.1,
-                },
-        );
+        // same size as the box hitbox it replaced, 4.2 wide and 200.02 tall
+        let radius = 2.1;
+        let half_segment = Vec3 {
+            x: 0.0,
+            y: 100.01 - radius,
+            z: 0.0,
+        };
         Self {
             position,
             pitch_deg: 0.0,
             yaw_deg: 0.0,
-            hitbox: hitbox,
+            capsule: Capsule::new(position - half_segment, position + half_segment, radius),
         }
     }
 }
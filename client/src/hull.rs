@@ -0,0 +1,224 @@
+#![allow(unused, dead_code)]
+use std::collections::HashMap;
+use ultraviolet::Vec3;
+
+/*
+    convex_hull (incremental):
+    0. tetrahedron between extreme points is the first hull, faces are counter-clockwise seen
+       from outside
+    1. every other point takes away faces it is in front of, their outline (horizon) is joined
+       to the point with new faces
+    2. faces (almost) in the plane of the point can be left inside of the ones it is in front of,
+       they are taken away too, so the horizon stays one loop
+    3. points behind every face are inside and left out
+    4. vertices of the remaining faces are the hull
+*/
+
+// part of the size of the point cloud, points closer than this to a face plane are on it
+const HULL_EPSILON: f32 = 1e-5;
+
+struct Face {
+    vertices: [usize; 3],
+    normal: Vec3,
+    distance: f32,
+}
+
+impl Face {
+    fn new(points: &[Vec3], vertices: [usize; 3]) -> Face {
+        let [a, b, c] = vertices.map(|vertex| points[vertex]);
+        let normal = (b - a).cross(c - a).normalized();
+        Face {
+            vertices,
+            normal,
+            distance: normal.dot(a),
+        }
+    }
+
+    // Face turned so that the point is behind it
+    fn facing_away(points: &[Vec3], [a, b, c]: [usize; 3], behind: usize) -> Face {
+        let face = Face::new(points, [a, b, c]);
+        if face.height(points[behind]) > 0.0 {
+            Face::new(points, [a, c, b])
+        } else {
+            face
+        }
+    }
+
+    // Signed distance of the point from the face plane, positive in front
+    fn height(&self, point: Vec3) -> f32 {
+        self.normal.dot(point) - self.distance
+    }
+
+    fn edges(&self) -> [(usize, usize); 3] {
+        let [a, b, c] = self.vertices;
+        [(a, b), (b, c), (c, a)]
+    }
+}
+
+// Marks faces reachable from start through edges of faces passing enters
+fn flood(
+    faces: &[Face],
+    neighbors: &HashMap<(usize, usize), usize>,
+    start: usize,
+    enters: impl Fn(usize) -> bool,
+    marked: &mut [bool],
+) -> usize {
+    let mut count = 0;
+    let mut stack = vec![start];
+    marked[start] = true;
+    while let Some(face) = stack.pop() {
+        count += 1;
+        for (from, to) in faces[face].edges() {
+            let neighbor = neighbors[&(to, from)];
+            if !marked[neighbor] && enters(neighbor) {
+                marked[neighbor] = true;
+                stack.push(neighbor);
+            }
+        }
+    }
+    count
+}
+
+// Vertices of the convex hull around points
+// Flat clouds have no tetrahedron to start from, every point is kept for them (still fine for
+// support functions, only slower)
+pub fn convex_hull(points: &[Vec3]) -> Vec<Vec3> {
+    if points.len() < 4 {
+        return points.to_vec();
+    }
+
+    let mut min = [0; 3];
+    let mut max = [0; 3];
+    for (index, point) in points.iter().enumerate() {
+        for axis in 0..3 {
+            if point[axis] < points[min[axis]][axis] {
+                min[axis] = index;
+            }
+            if point[axis] > points[max[axis]][axis] {
+                max[axis] = index;
+            }
+        }
+    }
+    let spread = |axis: usize| points[max[axis]][axis] - points[min[axis]][axis];
+    let axis = (0..3)
+        .max_by(|&a, &b| spread(a).total_cmp(&spread(b)))
+        .unwrap();
+    if spread(axis) <= 0.0 {
+        return vec![points[0]];
+    }
+    let epsilon = HULL_EPSILON * spread(axis);
+
+    // widest pair, point farthest from their line, point farthest from the plane of the three
+    let (a, b) = (min[axis], max[axis]);
+    let line = (points[b] - points[a]).normalized();
+    let from_line = |point: Vec3| {
+        let along = point - points[a];
+        (along - line * along.dot(line)).mag()
+    };
+    let c = (0..points.len())
+        .max_by(|&x, &y| from_line(points[x]).total_cmp(&from_line(points[y])))
+        .unwrap();
+    if from_line(points[c]) <= epsilon {
+        return vec![points[a], points[b]];
+    }
+    let base = Face::new(points, [a, b, c]);
+    let d = (0..points.len())
+        .max_by(|&x, &y| {
+            base.height(points[x])
+                .abs()
+                .total_cmp(&base.height(points[y]).abs())
+        })
+        .unwrap();
+    if base.height(points[d]).abs() <= epsilon {
+        return points.to_vec();
+    }
+
+    let mut faces = vec![
+        Face::facing_away(points, [a, b, c], d),
+        Face::facing_away(points, [a, b, d], c),
+        Face::facing_away(points, [a, c, d], b),
+        Face::facing_away(points, [b, c, d], a),
+    ];
+    for (index, &point) in points.iter().enumerate() {
+        let heights: Vec<f32> = faces.iter().map(|face| face.height(point)).collect();
+        let farthest = (0..faces.len())
+            .max_by(|&x, &y| heights[x].total_cmp(&heights[y]))
+            .unwrap();
+        if heights[farthest] <= epsilon {
+            continue;
+        }
+
+        // face on the other side of every edge
+        let neighbors: HashMap<(usize, usize), usize> = faces
+            .iter()
+            .enumerate()
+            .flat_map(|(index, face)| face.edges().map(|edge| (edge, index)))
+            .collect();
+        let mut visible = vec![false; faces.len()];
+        flood(
+            &faces,
+            &neighbors,
+            farthest,
+            |face| heights[face] > epsilon,
+            &mut visible,
+        );
+        // the biggest group of the other faces stays, smaller ones are holes in the visible faces
+        let mut seen = visible.clone();
+        let mut kept = (0, visible.clone());
+        while let Some(face) = seen.iter().position(|seen| !seen) {
+            let mut group = visible.clone();
+            let count = flood(&faces, &neighbors, face, |_| true, &mut group);
+            for (seen, grouped) in seen.iter_mut().zip(&group) {
+                *seen |= grouped;
+            }
+            if count > kept.0 {
+                kept = (count, group);
+            }
+        }
+        for (visible, kept) in visible.iter_mut().zip(&kept.1) {
+            *visible |= !kept;
+        }
+
+        let horizon: Vec<(usize, usize)> = (0..faces.len())
+            .filter(|&face| visible[face])
+            .flat_map(|face| faces[face].edges())
+            .filter(|(from, to)| !visible[neighbors[&(*to, *from)]])
+            .collect();
+        let mut visible = visible.into_iter();
+        faces.retain(|_| !visible.next().unwrap());
+        for (from, to) in horizon {
+            faces.push(Face::new(points, [from, to, index]));
+        }
+    }
+
+    let mut vertices: Vec<usize> = faces.iter().flat_map(|face| face.vertices).collect();
+    vertices.sort_unstable();
+    vertices.dedup();
+    vertices.into_iter().map(|vertex| points[vertex]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interior_points_are_left_out() {
+        let mut points: Vec<Vec3> = (0..8)
+            .map(|corner| {
+                Vec3::new(
+                    (corner & 1) as f32,
+                    ((corner >> 1) & 1) as f32,
+                    ((corner >> 2) & 1) as f32,
+                )
+            })
+            .collect();
+        points.extend([
+            Vec3::broadcast(0.5),
+            Vec3::new(0.25, 0.5, 0.75),
+            Vec3::new(0.5, 0.5, 1.0),
+        ]);
+        let hull = convex_hull(&points);
+        assert_eq!(hull.len(), 8);
+        assert!(points[..8].iter().all(|corner| hull.contains(corner)));
+    }
+}
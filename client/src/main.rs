@@ -5,9 +5,17 @@ use beryllium::events::Event;
 use beryllium::*;
 use gl33::*;
 mod batching;
+mod broadphase;
+mod chunked;
 mod collision;
 mod helper;
+mod hull;
+mod mesh;
 mod object;
+mod optimize;
+mod simplify;
+mod transform;
+mod vertex;
 use std::collections::HashSet;
 use std::fs;
 use ultraviolet::*;
@@ -15,6 +23,11 @@ use ultraviolet::*;
 const OBJ_AMOUNT: usize = 4;
 
 fn main() {
+    if std::env::args().any(|arg| arg == "--bench-transform") {
+        transform::benchmark("obj/car.obj", 16, 20);
+        return;
+    }
+
     let sdl = Sdl::init(init::InitFlags::EVERYTHING);
     sdl.set_gl_context_major_version(3).unwrap();
     sdl.set_gl_context_major_version(3).unwrap();
@@ -30,29 +43,30 @@ fn main() {
     let gl = helper::GlFnsWin::new(&sdl);
     gl.enable(GL_DEPTH_TEST);
 
-    let mut batcher = batching::DynamicBatch::new();
+    // brick mesh is uploaded once, every brick of the grid is an instance of it
+    let mut mesh_cache = mesh::MeshCache::new();
+    let brick = mesh_cache.batch_object("obj/brick.obj", Vec3::zero());
+    let mut batcher = batching::InstancedBatch::new(brick).unwrap();
+    let mut bricks = vec![];
     for i in 0..OBJ_AMOUNT {
         for j in 0..OBJ_AMOUNT {
-            let batch_obj_temp = batching::BatchObject::new(
-                gl.fns.clone(),
-                "obj/brick.obj",
-                Vec3 {
-                    x: 3.0 + 3.0 * i as f32,
-                    y: 3.0 + 3.0 * j as f32,
-                    z: 6.0,
-                },
-            );
             let mut name: String = "asd".to_owned();
             name.push_str(&i.to_string());
             name.push_str(" ");
             name.push_str(&j.to_string());
-            batcher.consume_object(batch_obj_temp, &name);
+            let position = Vec3 {
+                x: 3.0 + 3.0 * i as f32,
+                y: 3.0 + 3.0 * j as f32,
+                z: 6.0,
+            };
+            batcher.add_instance(&name, position, None).unwrap();
+            bricks.push(name);
         }
     }
 
-    batcher.send_data(gl.fns.clone(), "textures/red_brick.png");
+    batcher.send_data(gl.fns.clone(), Some("textures/red_brick.png"));
 
-    let vert_shader = fs::read_to_string("src/shader/vert.glsl").unwrap();
+    let vert_shader = fs::read_to_string("src/shader/instanced_vert.glsl").unwrap();
     let frag_shader = fs::read_to_string("src/shader/frag.glsl").unwrap();
 
     let shader_program =
@@ -123,12 +137,34 @@ fn main() {
         last_time = time / 10.0;
 
         if time - previous_time >= 1.0 {
-            println!("{}", frame_count);
+            println!("{} instances: {}", frame_count, batcher.instance_count());
+            // brick under the crosshair, by hitbox
+            let ray = camera.crosshair_ray();
+            let hit = bricks
+                .iter()
+                .filter_map(|name| {
+                    let distance = ray.intersect_box(batcher.hitbox_as_ref(name).unwrap())?;
+                    Some((name, distance))
+                })
+                .filter(|(_, distance)| *distance <= 100.0)
+                .min_by(|(_, a), (_, b)| a.total_cmp(b));
+            if let Some((name, distance)) = hit {
+                println!("looking at {} ({:.2} away)", name, distance);
+            }
             previous_time = time;
             frame_count = 0.0;
         }
 
-        camera.update_position(&keys_held, camera_speed * delta_time);
+        // colliders the camera can reach this frame
+        let step = camera_speed * delta_time;
+        let reach = camera.hitbox().grown(step);
+        let nearby: Vec<collision::Collider> = bricks
+            .iter()
+            .map(|name| *batcher.hitbox_as_ref(name).unwrap())
+            .filter(|hitbox| hitbox.colide(&reach))
+            .map(collision::Collider::Aabb)
+            .collect();
+        camera.update_position_colliding(&keys_held, step, &nearby);
 
         let view: Mat4 = camera.make_view_matrix();
 
@@ -151,21 +187,8 @@ fn main() {
             z: 0.0,
         });
 
-        for i in 0..OBJ_AMOUNT {
-            for j in 0..OBJ_AMOUNT {
-                let mut name: String = "asd".to_owned();
-                name.push_str(&i.to_string());
-                name.push_str(" ");
-                name.push_str(&j.to_string());
-                batcher.hitbox_as_mut(&name).unwrap().transformation(model);
-                if camera.hitbox.colide(batcher.hitbox_as_ref(&name).unwrap()){
-                    println!("{}", name);
-
-                }
-                //batcher.change_position(gl.fns.clone(), &name, Vec3 { x: 5.0, y: 0.0, z: 0.0 });
-                //batcher.move_delta(gl.fns.clone(), &name, transformation);
-            }
-        }
+        //batcher.change_position(&bricks[0], Vec3 { x: 5.0, y: 0.0, z: 0.0 });
+        //batcher.move_delta(&bricks[0], transformation);
 
         //        helper::print_error(gl.fns.clone());
         gl.win.swap_window();
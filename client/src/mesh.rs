@@ -0,0 +1,225 @@
+#![allow(unused, dead_code)]
+use crate::batching::{BatchObject, MeshPart};
+use crate::collision::Box3D;
+use crate::simplify::append_lods;
+use crate::vertex::{Semantic, VertexLayout};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+use ultraviolet::{Vec3, Vec4};
+
+// Parsed OBJ file in the batched vertex format, positions are as in the file (not moved)
+#[derive(Debug, Clone)]
+pub struct MeshData {
+    pub vertex_data: Vec<f32>,
+    pub indices: Vec<u32>,
+    pub layout: VertexLayout,
+    pub hitbox: Box3D,
+    pub parts: Vec<MeshPart>,
+    // index ranges of levels of detail, lods[0] is the mesh itself and parts are in it
+    pub lods: Vec<Range<usize>>,
+}
+
+impl MeshData {
+    pub fn load(obj_path: &str) -> Self {
+        Self::load_with_layout(obj_path, VertexLayout::standard())
+    }
+
+    // Semantics the OBJ file has (position, normal, first uv set, material colour) are filled,
+    // the rest of the layout is zero, object index is -1
+    // name_lod1.obj, name_lod2.obj, ... next to name.obj are loaded as further levels of detail
+    pub fn load_with_layout(obj_path: &str, layout: VertexLayout) -> Self {
+        let mut mesh = Self::load_single(obj_path, layout.clone());
+        let stem = obj_path.strip_suffix(".obj").unwrap_or(obj_path);
+        let mut level = 1;
+        while Path::new(&format!("{}_lod{}.obj", stem, level)).exists() {
+            mesh.add_lod(&Self::load_single(
+                &format!("{}_lod{}.obj", stem, level),
+                layout.clone(),
+            ));
+            level += 1;
+        }
+        mesh
+    }
+
+    // Vertices and indices of the coarser mesh are appended, its parts are dropped
+    pub fn add_lod(&mut self, lod: &MeshData) {
+        if lod.layout != self.layout {
+            panic!("LOD layout differs from layout of the mesh!");
+        }
+        let offset = (self.vertex_data.len() / self.layout.floats()) as u32;
+        let start = self.indices.len();
+        self.vertex_data.extend_from_slice(&lod.vertex_data);
+        self.indices.extend(
+            lod.indices[lod.lods[0].clone()]
+                .iter()
+                .map(|index| index + offset),
+        );
+        self.lods.push(start..self.indices.len());
+    }
+
+    // Coarser levels of detail generated from the full mesh, ratio is the part of triangles kept
+    pub fn generate_lods(&mut self, ratios: &[f32]) {
+        append_lods(
+            &self.layout,
+            &self.vertex_data,
+            &mut self.indices,
+            &mut self.lods,
+            ratios,
+        );
+    }
+
+    fn load_single(obj_path: &str, layout: VertexLayout) -> Self {
+        let (models, materials) = tobj::load_obj(
+            obj_path,
+            &tobj::LoadOptions {
+                triangulate: true,
+                single_index: true,
+                ..Default::default()
+            },
+        )
+        .expect("Failed to load OBJ");
+        // missing .mtl file is not an error, the model is just drawn white
+        let materials = materials.unwrap_or_default();
+
+        let mut vertex_data: Vec<f32> = vec![];
+        let mut indices: Vec<u32> = vec![];
+        let mut parts: Vec<MeshPart> = vec![];
+        let mut min_vertex: Vec3 = Vec3::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut max_vertex: Vec3 = Vec3::new(f32::MIN, f32::MIN, f32::MIN);
+
+        // every model has its own local indices, they are shifted by the amount of vertices
+        // already merged
+        for model in &models {
+            let mesh = &model.mesh;
+            let positions = &mesh.positions;
+            let normals = &mesh.normals;
+            let texcoords = &mesh.texcoords;
+
+            let color = material_color(&materials, mesh.material_id);
+
+            let vertex_offset = vertex_data.len() / layout.floats();
+            let index_offset = indices.len();
+            let mut part_min: Vec3 = Vec3::new(f32::MAX, f32::MAX, f32::MAX);
+            let mut part_max: Vec3 = Vec3::new(f32::MIN, f32::MIN, f32::MIN);
+
+            for i in 0..(positions.len() / 3) {
+                let vert = Vec3::new(positions[3 * i], positions[3 * i + 1], positions[3 * i + 2]);
+
+                part_min = part_min.min_by_component(vert);
+                part_max = part_max.max_by_component(vert);
+
+                // some groups come without normals or uvs
+                let norm = if normals.len() >= 3 * i + 3 {
+                    Vec3::new(normals[3 * i], normals[3 * i + 1], normals[3 * i + 2])
+                } else {
+                    Vec3::zero()
+                };
+                let (u, v) = if texcoords.len() >= 2 * i + 2 {
+                    (texcoords[2 * i], texcoords[2 * i + 1])
+                } else {
+                    (0.0, 0.0)
+                };
+
+                let mut vertex = vec![0.0; layout.floats()];
+                layout.write(&mut vertex, Semantic::Position, vert.as_slice());
+                layout.write(&mut vertex, Semantic::Normal, norm.as_slice());
+                layout.write(&mut vertex, Semantic::TexCoord(0), &[u, v]);
+                layout.write(&mut vertex, Semantic::Color, color.as_slice());
+                layout.write(&mut vertex, Semantic::ObjectIndex, &[-1.0]);
+                vertex_data.extend_from_slice(&vertex);
+            }
+
+            let offset = vertex_offset as u32;
+            indices.extend(mesh.indices.iter().map(|index| index + offset));
+
+            min_vertex = min_vertex.min_by_component(part_min);
+            max_vertex = max_vertex.max_by_component(part_max);
+
+            // tobj splits groups on every usemtl, so the same name can show up more than once
+            let mut name = model.name.clone();
+            let mut duplicate = 1;
+            while parts.iter().any(|part| part.name == name) {
+                name = format!("{}.{}", model.name, duplicate);
+                duplicate += 1;
+            }
+
+            parts.push(MeshPart {
+                name,
+                vertex_offset,
+                vertex_count: positions.len() / 3,
+                index_offset,
+                index_count: mesh.indices.len(),
+                hitbox: Box3D::new(part_max, part_min),
+            });
+        }
+
+        Self {
+            lods: vec![Range {
+                start: 0,
+                end: indices.len(),
+            }],
+            vertex_data,
+            indices,
+            layout,
+            hitbox: Box3D::new(max_vertex, min_vertex),
+            parts,
+        }
+    }
+}
+
+// Colour-only materials (Kd without map_Kd) are baked into vertices, textured ones stay white so
+// the texture is not tinted
+fn material_color(materials: &[tobj::Material], material_id: Option<usize>) -> Vec3 {
+    match material_id.and_then(|id| materials.get(id)) {
+        Some(material) if material.diffuse_texture.is_none() => {
+            material.diffuse.map(Vec3::from).unwrap_or(Vec3::one())
+        }
+        _ => Vec3::one(),
+    }
+}
+
+// Every OBJ file is parsed once, later requests for the same path share the parsed data
+// Every mesh of one cache is loaded with the same layout
+pub struct MeshCache {
+    meshes: HashMap<String, Rc<MeshData>>,
+    layout: VertexLayout,
+}
+
+impl MeshCache {
+    pub fn new() -> Self {
+        Self::with_layout(VertexLayout::standard())
+    }
+
+    pub fn with_layout(layout: VertexLayout) -> Self {
+        Self {
+            meshes: HashMap::new(),
+            layout,
+        }
+    }
+
+    pub fn get(&mut self, obj_path: &str) -> Rc<MeshData> {
+        match self.meshes.get(obj_path) {
+            Some(mesh) => mesh.clone(),
+            None => {
+                let mesh = Rc::new(MeshData::load_with_layout(obj_path, self.layout.clone()));
+                self.meshes.insert(obj_path.to_string(), mesh.clone());
+                mesh
+            }
+        }
+    }
+
+    pub fn batch_object(&mut self, obj_path: &str, position: Vec3) -> BatchObject {
+        BatchObject::from_mesh(&self.get(obj_path), position)
+    }
+
+    // Drops the cached mesh, BatchObjects made from it keep their own copies
+    pub fn evict(&mut self, obj_path: &str) {
+        self.meshes.remove(obj_path);
+    }
+
+    pub fn len(&self) -> usize {
+        self.meshes.len()
+    }
+}
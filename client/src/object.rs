@@ -3,6 +3,7 @@
 * elegant and change if performance issue met)
 */
 #![allow(unused, dead_code)]
+use crate::batching::BatchObject;
 use crate::helper::{self, create_texture};
 use gl33::*;
 use std::rc::Rc;
@@ -19,57 +20,14 @@ pub struct Object {
 
 impl Object {
     pub fn new(gl: Rc<GlFns>, obj_path: &str, texture_png_path: &str, position: Vec3) -> Self {
-        let (models, _) = tobj::load_obj(
-            obj_path,
-            &tobj::LoadOptions {
-                triangulate: true,
-                single_index: true,
-                ..Default::default()
-            },
-        )
-        .expect("Failed to load OBJ");
-
-        let mesh = &models[0].mesh;
-
-        let positions = &mesh.positions;
-        let normals = &mesh.normals;
-        let texcoords = &mesh.texcoords;
-        let indices = mesh.indices.clone();
-        /*
-                println!(
-                    "positions: {} normals: {} texcoords: {} indices: {}",
-                    positions.len(),
-                    normals.len(),
-                    texcoords.len(),
-                    indices.len()
-                );
-        */
-
-        let mut vertex_data: Vec<f32> = vec![];
-        for i in 0..(positions.len() / 3) {
-            let transform = Mat4::from_translation(position);
-
-            let vec3 = Vec3::from(
-                transform
-                    * Vec4::new(
-                        positions[3 * i],
-                        positions[3 * i + 1],
-                        positions[3 * i + 2],
-                        1.0,
-                    ),
-            );
-
-            vertex_data.push(vec3.x);
-            vertex_data.push(vec3.y);
-            vertex_data.push(vec3.z);
-
-            vertex_data.push(normals[3 * i]);
-            vertex_data.push(normals[3 * i + 1]);
-            vertex_data.push(normals[3 * i + 2]);
-
-            vertex_data.push(texcoords[2 * i]);
-            vertex_data.push(texcoords[2 * i + 1]);
-        }
+        // same loading as batches, so every model of the OBJ file ends up in one buffer
+        let BatchObject {
+            vertex_data,
+            indices,
+            layout,
+            lods,
+            ..
+        } = BatchObject::new(gl.clone(), obj_path, position);
 
         let vao = helper::VertexArray::new(gl.clone()).expect("Couldn`t make a VAO");
         vao.bind();
@@ -94,40 +52,11 @@ impl Object {
             GL_STATIC_DRAW,
         );
 
-        unsafe {
-            gl.VertexAttribPointer(
-                0,
-                3,
-                GL_FLOAT,
-                0,
-                size_of::<[f32; 8]>().try_into().unwrap(),
-                0 as *const _,
-            );
-            gl.EnableVertexAttribArray(0);
-
-            gl.VertexAttribPointer(
-                1,
-                3,
-                GL_FLOAT,
-                0,
-                size_of::<[f32; 8]>().try_into().unwrap(),
-                size_of::<[f32; 3]>() as *const _,
-            );
-            gl.EnableVertexAttribArray(1);
-
-            gl.VertexAttribPointer(
-                2,
-                2,
-                GL_FLOAT,
-                0,
-                size_of::<[f32; 8]>().try_into().unwrap(),
-                size_of::<[f32; 6]>() as *const _,
-            );
-            gl.EnableVertexAttribArray(2);
-        }
+        layout.apply(&gl);
         create_texture(gl, texture_png_path);
         return Self {
-            indices_len: indices.len().try_into().unwrap(),
+            // coarser levels of detail follow the full mesh, only it is drawn
+            indices_len: lods[0].len().try_into().unwrap(),
             ebo: ebo,
             vbo: vbo,
             vao: vao,
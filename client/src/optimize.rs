@@ -0,0 +1,274 @@
+#![allow(unused, dead_code)]
+use std::collections::VecDeque;
+
+/*
+    optimize:
+    0. optimize_vertex_cache orders triangles for the post-transform vertex cache (Forsyth's
+       linear-speed algorithm), triangles stay the same, only their order changes
+    1. optimize_vertex_fetch orders vertices by first use, so the GPU reads the buffer forward
+    2. acmr simulates FIFO cache, average transformed vertices per triangle (0.5 - 3.0)
+*/
+
+// Forsyth's scoring, cache size is what the scores are made for, not the size of real GPU cache
+const CACHE_SIZE: usize = 32;
+const CACHE_DECAY_POWER: f32 = 1.5;
+const LAST_TRIANGLE_SCORE: f32 = 0.75;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+// cache size for acmr reports
+pub const ACMR_CACHE_SIZE: usize = 16;
+
+// ACMR before and after the optimisation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheReport {
+    pub before: f32,
+    pub after: f32,
+}
+
+// Average cache misses per triangle of FIFO cache of cache_size vertices
+pub fn acmr(indices: &[u32], cache_size: usize) -> f32 {
+    let triangles = indices.len() / 3;
+    if triangles == 0 {
+        return 0.0;
+    }
+    let mut cache: VecDeque<u32> = VecDeque::new();
+    let mut misses = 0;
+    for &index in indices {
+        if !cache.contains(&index) {
+            misses += 1;
+            cache.push_back(index);
+            if cache.len() > cache_size {
+                cache.pop_front();
+            }
+        }
+    }
+    misses as f32 / triangles as f32
+}
+
+fn vertex_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32 {
+    if remaining_triangles == 0 {
+        return -1.0;
+    }
+    let cache_score = match cache_position {
+        // the last triangle used these, they get a fixed score so the next one does not reuse
+        // the same edge all the time
+        Some(position) if position < 3 => LAST_TRIANGLE_SCORE,
+        Some(position) => {
+            let scale = 1.0 / (CACHE_SIZE - 3) as f32;
+            (1.0 - (position - 3) as f32 * scale).powf(CACHE_DECAY_POWER)
+        }
+        None => 0.0,
+    };
+    // vertices with few triangles left are finished first, so they leave no lone triangles
+    cache_score + VALENCE_BOOST_SCALE * (remaining_triangles as f32).powf(-VALENCE_BOOST_POWER)
+}
+
+// Triangles of indices (every index < vertex_count) in cache friendly order
+pub fn optimize_vertex_cache(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 {
+        return indices.to_vec();
+    }
+
+    let mut vertex_triangles: Vec<Vec<usize>> = vec![vec![]; vertex_count];
+    for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+        for &vertex in corners {
+            vertex_triangles[vertex as usize].push(triangle);
+        }
+    }
+    let mut remaining: Vec<usize> = vertex_triangles.iter().map(|list| list.len()).collect();
+    let mut vertex_scores: Vec<f32> = (0..vertex_count)
+        .map(|vertex| vertex_score(None, remaining[vertex]))
+        .collect();
+    let mut triangle_scores: Vec<f32> = indices
+        .chunks_exact(3)
+        .map(|corners| {
+            corners
+                .iter()
+                .map(|&vertex| vertex_scores[vertex as usize])
+                .sum()
+        })
+        .collect();
+    let mut emitted = vec![false; triangle_count];
+    let mut cache: Vec<u32> = Vec::with_capacity(CACHE_SIZE + 3);
+    let mut result: Vec<u32> = Vec::with_capacity(indices.len());
+    // triangles before it are all emitted, used when nothing in the cache has triangles left
+    let mut scan = 0;
+
+    let mut best: Option<usize> = None;
+    for _ in 0..triangle_count {
+        let triangle = match best {
+            Some(triangle) => triangle,
+            None => {
+                // best of every triangle left, this happens only when cache runs dry
+                let mut best_score = f32::MIN;
+                let mut best_triangle = None;
+                while scan < triangle_count && emitted[scan] {
+                    scan += 1;
+                }
+                for triangle in scan..triangle_count {
+                    if !emitted[triangle] && triangle_scores[triangle] > best_score {
+                        best_score = triangle_scores[triangle];
+                        best_triangle = Some(triangle);
+                    }
+                }
+                match best_triangle {
+                    Some(triangle) => triangle,
+                    None => break,
+                }
+            }
+        };
+
+        emitted[triangle] = true;
+        let corners = &indices[triangle * 3..triangle * 3 + 3];
+        result.extend_from_slice(corners);
+
+        // corners go to the front of the cache, the rest keeps its order
+        let mut new_cache: Vec<u32> = corners.to_vec();
+        new_cache.extend(
+            cache
+                .iter()
+                .copied()
+                .filter(|vertex| !corners.contains(vertex)),
+        );
+        for &vertex in corners {
+            remaining[vertex as usize] -= 1;
+            let list = &mut vertex_triangles[vertex as usize];
+            if let Some(at) = list.iter().position(|&other| other == triangle) {
+                list.swap_remove(at);
+            }
+        }
+
+        // scores change for everything which was or is in the cache
+        for (position, &vertex) in new_cache.iter().enumerate() {
+            let position = (position < CACHE_SIZE).then_some(position);
+            let score = vertex_score(position, remaining[vertex as usize]);
+            let delta = score - vertex_scores[vertex as usize];
+            vertex_scores[vertex as usize] = score;
+            for &other in &vertex_triangles[vertex as usize] {
+                triangle_scores[other] += delta;
+            }
+        }
+        new_cache.truncate(CACHE_SIZE);
+        cache = new_cache;
+
+        // next triangle is the best one around cached vertices
+        best = None;
+        let mut best_score = f32::MIN;
+        for &vertex in &cache {
+            for &other in &vertex_triangles[vertex as usize] {
+                if triangle_scores[other] > best_score {
+                    best_score = triangle_scores[other];
+                    best = Some(other);
+                }
+            }
+        }
+    }
+    result
+}
+
+// Reorders vertices (floats per vertex) by first use in indices and rewrites indices, vertices
+// nothing uses go to the end
+pub fn optimize_vertex_fetch(floats: usize, vertex_data: &mut [f32], indices: &mut [u32]) {
+    let vertex_count = vertex_data.len() / floats;
+    let mut remap: Vec<Option<u32>> = vec![None; vertex_count];
+    let mut order: Vec<usize> = Vec::with_capacity(vertex_count);
+    for index in indices.iter_mut() {
+        let new_index = *remap[*index as usize].get_or_insert_with(|| {
+            order.push(*index as usize);
+            (order.len() - 1) as u32
+        });
+        *index = new_index;
+    }
+    for (vertex, new_index) in remap.iter_mut().enumerate() {
+        if new_index.is_none() {
+            *new_index = Some(order.len() as u32);
+            order.push(vertex);
+        }
+    }
+
+    let old = vertex_data.to_vec();
+    for (new_vertex, &old_vertex) in order.iter().enumerate() {
+        vertex_data[new_vertex * floats..(new_vertex + 1) * floats]
+            .copy_from_slice(&old[old_vertex * floats..(old_vertex + 1) * floats]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Triangles of a cells x cells grid row by row, ((cells + 1)^2 vertices)
+    fn grid(cells: u32) -> Vec<u32> {
+        let mut indices = vec![];
+        for y in 0..cells {
+            for x in 0..cells {
+                let [a, b, c, d] = [(x, y), (x + 1, y), (x + 1, y + 1), (x, y + 1)]
+                    .map(|(x, y)| y * (cells + 1) + x);
+                indices.extend([a, b, c, a, c, d]);
+            }
+        }
+        indices
+    }
+
+    // Triangles turned to start at their smallest index (winding kept) and sorted
+    fn triangles(indices: &[u32]) -> Vec<[u32; 3]> {
+        let mut triangles: Vec<[u32; 3]> = indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                let first = (0..3).min_by_key(|&at| triangle[at]).unwrap();
+                [0, 1, 2].map(|at| triangle[(first + at) % 3])
+            })
+            .collect();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn acmr_of_strip_and_quad() {
+        // strip of 4 triangles, 6 vertices each loaded once
+        assert_eq!(acmr(&[0, 1, 2, 1, 2, 3, 2, 3, 4, 3, 4, 5], 3), 1.5);
+        // quad, 4 vertices, but with 2 vertices of cache 0 is pushed out by 2 before the second
+        // triangle needs it
+        assert_eq!(acmr(&[0, 1, 2, 0, 2, 3], 3), 2.0);
+        assert_eq!(acmr(&[0, 1, 2, 0, 2, 3], 2), 2.5);
+        assert_eq!(acmr(&[], ACMR_CACHE_SIZE), 0.0);
+    }
+
+    #[test]
+    fn cache_order_keeps_triangles_and_lowers_acmr() {
+        let cells = 32;
+        let indices = grid(cells);
+        let vertex_count = ((cells + 1) * (cells + 1)) as usize;
+        let optimized = optimize_vertex_cache(&indices, vertex_count);
+        assert_eq!(triangles(&optimized), triangles(&indices));
+
+        let before = acmr(&indices, ACMR_CACHE_SIZE);
+        let after = acmr(&optimized, ACMR_CACHE_SIZE);
+        assert!(after <= before, "{} > {}", after, before);
+    }
+
+    #[test]
+    fn fetch_order_is_permutation_of_vertices() {
+        let floats = 2;
+        // vertex 4 is not used by any triangle
+        let mut indices = vec![3, 1, 0, 3, 0, 5, 2, 5, 0];
+        let vertex_data: Vec<f32> = (0..6)
+            .flat_map(|vertex| [vertex as f32, vertex as f32 * 10.0])
+            .collect();
+        let mut remapped = vertex_data.clone();
+        let old_indices = indices.clone();
+        optimize_vertex_fetch(floats, &mut remapped, &mut indices);
+
+        // first use order, the unused one at the end
+        assert_eq!(indices, vec![0, 1, 2, 0, 2, 3, 4, 3, 2]);
+        let vertex = |data: &[f32], index: u32| {
+            data[index as usize * floats..(index as usize + 1) * floats].to_vec()
+        };
+        let order: Vec<f32> = (0..6).map(|index| vertex(&remapped, index)[0]).collect();
+        assert_eq!(order, vec![3.0, 1.0, 0.0, 5.0, 2.0, 4.0]);
+        for (&new, &old) in indices.iter().zip(&old_indices) {
+            assert_eq!(vertex(&remapped, new), vertex(&vertex_data, old));
+        }
+    }
+}
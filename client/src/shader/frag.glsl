@@ -4,6 +4,7 @@ uniform sampler2D texture_image;
 in vec2 tex_cords;
 in vec3 normal_vec;
 in vec3 frag_pos;
+in vec3 vertex_color;
 out vec4 final_color;
 
 void main() {
@@ -22,7 +23,7 @@ void main() {
     vec3 diffuse = diff * light_color;
 
     // combine
-    vec3 result = (ambient + diffuse) * texture(texture_image, tex_cords).rgb;
+    vec3 result = (ambient + diffuse) * texture(texture_image, tex_cords).rgb * vertex_color;
 
     final_color = vec4(result, 1.0);
 }
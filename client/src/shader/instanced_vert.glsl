@@ -0,0 +1,26 @@
+#version 330 core
+uniform mat4 model;
+uniform mat4 view;
+uniform mat4 projection;
+
+layout(location = 0) in vec3 pos;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 tex;
+layout(location = 3) in vec3 color;
+// per instance (InstancedBatch)
+layout(location = 5) in mat4 instance_model;
+layout(location = 9) in vec4 instance_tint;
+
+out vec2 tex_cords;
+out vec3 normal_vec;
+out vec3 frag_pos;
+out vec3 vertex_color;
+
+void main() {
+    mat4 full_model = model * instance_model;
+    frag_pos = vec3(full_model * vec4(pos, 1.0));
+    gl_Position = projection * view * vec4(frag_pos, 1.0);
+    tex_cords = tex;
+    vertex_color = color * instance_tint.rgb;
+    normal_vec = mat3(transpose(inverse(full_model))) * normal;
+}
@@ -2,19 +2,38 @@
 uniform mat4 model;
 uniform mat4 view;
 uniform mat4 projection;
+// model matrices of DynamicBatch objects (TransformMode::Gpu), 4 texels per matrix
+uniform samplerBuffer object_transforms;
 
 layout(location = 0) in vec3 pos;
 layout(location = 1) in vec3 normal;
 layout(location = 2) in vec2 tex;
+layout(location = 3) in vec3 color;
+layout(location = 4) in float object_index;
 
 out vec2 tex_cords;
 out vec3 normal_vec;
 out vec3 frag_pos;
+out vec3 vertex_color;
+
+mat4 object_model() {
+    if (object_index < 0.0) {
+        return mat4(1.0);
+    }
+    int base = int(object_index) * 4;
+    return mat4(
+        texelFetch(object_transforms, base),
+        texelFetch(object_transforms, base + 1),
+        texelFetch(object_transforms, base + 2),
+        texelFetch(object_transforms, base + 3)
+    );
+}
 
 void main() {
-    
-    frag_pos = vec3(model * vec4(pos, 1.0));
+    mat4 full_model = model * object_model();
+    frag_pos = vec3(full_model * vec4(pos, 1.0));
     gl_Position = projection * view * vec4(frag_pos, 1.0);
     tex_cords = tex;
-    normal_vec = mat3(transpose(inverse(model))) * normal;
+    vertex_color = color;
+    normal_vec = mat3(transpose(inverse(full_model))) * normal;
 }
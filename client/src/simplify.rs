@@ -0,0 +1,601 @@
+#![allow(unused, dead_code)]
+use crate::vertex::VertexLayout;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::ops::Range;
+use ultraviolet::Vec3;
+
+/*
+    simplify_indices:
+    0. quadric error metric (Garland/Heckbert) with half-edge collapses on vertices welded by
+       position, every vertex of the removed position moves onto a vertex of its neighbour, so no
+       vertex is made and LODs share vertex buffer of the full mesh
+    1. vertex moves onto the neighbour vertex it shares a triangle with, or else onto one with
+       the same UV and colour, collapse across a UV seam has neither and is refused
+    2. open borders get an extra plane along the border, so the outline does not shrink
+    3. collapse which flips or squashes a triangle is refused
+    4. flat shaded meshes (brick.obj, cube.obj) have a vertex per face at every position, each
+       with its own UV, the one on a face not touching the other position has no vertex with its
+       UV there, so by 1. nothing collapses and they keep all of their triangles
+*/
+
+// weight of border planes against planes of the triangles
+const BOUNDARY_WEIGHT: f64 = 100.0;
+// squashed triangles (cosine between normal before and after) are refused like flipped ones
+const MIN_NORMAL_COSINE: f32 = 0.2;
+
+// Symmetric 4x4 matrix, upper triangle row by row
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn from_plane(normal: Vec3, point: Vec3, weight: f64) -> Self {
+        let (a, b, c) = (normal.x as f64, normal.y as f64, normal.z as f64);
+        let d = -(normal.dot(point) as f64);
+        Self(
+            [
+                a * a,
+                a * b,
+                a * c,
+                a * d,
+                b * b,
+                b * c,
+                b * d,
+                c * c,
+                c * d,
+                d * d,
+            ]
+            .map(|value| value * weight),
+        )
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for (value, other) in self.0.iter_mut().zip(other.0) {
+            *value += other;
+        }
+    }
+
+    // Sum of squared distances of point from all planes of the quadric
+    fn error(&self, point: Vec3) -> f64 {
+        let (x, y, z) = (point.x as f64, point.y as f64, point.z as f64);
+        let q = &self.0;
+        q[0] * x * x
+            + 2.0 * q[1] * x * y
+            + 2.0 * q[2] * x * z
+            + 2.0 * q[3] * x
+            + q[4] * y * y
+            + 2.0 * q[5] * y * z
+            + 2.0 * q[6] * y
+            + q[7] * z * z
+            + 2.0 * q[8] * z
+            + q[9]
+    }
+}
+
+// Collapse of position group from onto group to, versions tell apart candidates made before
+// either group changed
+struct Candidate {
+    cost: f64,
+    from: u32,
+    to: u32,
+    from_version: u32,
+    to_version: u32,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost.total_cmp(&other.cost) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// reversed, BinaryHeap pops the cheapest collapse first
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+struct Simplifier<'a> {
+    vertices: Vec<&'a [f32]>,
+    // words of a vertex other than position and normal, they have to match across a collapse
+    attribute_words: Vec<usize>,
+    triangles: Vec<[u32; 3]>,
+    alive: Vec<bool>,
+    live_triangles: usize,
+    vertex_triangles: Vec<Vec<usize>>,
+    // vertices welded by position, quadrics and collapses are per group
+    group_of: Vec<u32>,
+    members: Vec<Vec<u32>>,
+    positions: Vec<Vec3>,
+    quadrics: Vec<Quadric>,
+    removed: Vec<bool>,
+    versions: Vec<u32>,
+    heap: BinaryHeap<Candidate>,
+}
+
+// Reduces indices (triangle list over vertex_data) to about target_ratio of its triangles, the
+// result indexes the same vertex_data
+pub fn simplify_indices(
+    layout: &VertexLayout,
+    vertex_data: &[f32],
+    indices: &[u32],
+    target_ratio: f32,
+) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    let target = ((triangle_count as f32 * target_ratio.clamp(0.0, 1.0)).round() as usize).max(1);
+    if target >= triangle_count {
+        return indices.to_vec();
+    }
+
+    let mut simplifier = Simplifier::new(layout, vertex_data, indices);
+    while simplifier.live_triangles > target {
+        let candidate = match simplifier.heap.pop() {
+            Some(candidate) => candidate,
+            None => break,
+        };
+        simplifier.try_collapse(candidate);
+    }
+    simplifier.indices()
+}
+
+// One coarser level of detail per ratio, made from lods[0] and appended to indices
+pub fn append_lods(
+    layout: &VertexLayout,
+    vertex_data: &[f32],
+    indices: &mut Vec<u32>,
+    lods: &mut Vec<Range<usize>>,
+    ratios: &[f32],
+) {
+    for &ratio in ratios {
+        let lod = simplify_indices(layout, vertex_data, &indices[lods[0].clone()], ratio);
+        let start = indices.len();
+        indices.extend(lod);
+        lods.push(start..indices.len());
+    }
+}
+
+// Position bits are the key, so only exactly equal positions are welded
+fn position_key(position: Vec3) -> [u32; 3] {
+    [
+        position.x.to_bits(),
+        position.y.to_bits(),
+        position.z.to_bits(),
+    ]
+}
+
+impl<'a> Simplifier<'a> {
+    fn new(layout: &VertexLayout, vertex_data: &'a [f32], indices: &[u32]) -> Self {
+        let (position, normal) = layout.position_normal();
+        let floats = layout.floats();
+        let vertices: Vec<&[f32]> = vertex_data.chunks_exact(floats).collect();
+        let attribute_words = (0..floats)
+            .filter(|word| !(position..position + 3).contains(word))
+            .filter(|word| normal.is_none_or(|normal| !(normal..normal + 3).contains(word)))
+            .collect();
+
+        let mut group_of = vec![0u32; vertices.len()];
+        let mut members: Vec<Vec<u32>> = vec![];
+        let mut positions: Vec<Vec3> = vec![];
+        let mut groups: HashMap<[u32; 3], u32> = HashMap::new();
+        for (index, vertex) in vertices.iter().enumerate() {
+            let point = Vec3::new(vertex[position], vertex[position + 1], vertex[position + 2]);
+            let group = *groups.entry(position_key(point)).or_insert_with(|| {
+                members.push(vec![]);
+                positions.push(point);
+                (members.len() - 1) as u32
+            });
+            group_of[index] = group;
+            members[group as usize].push(index as u32);
+        }
+
+        let triangles: Vec<[u32; 3]> = indices
+            .chunks_exact(3)
+            .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+            .collect();
+        let mut vertex_triangles = vec![vec![]; vertices.len()];
+        let mut quadrics = vec![Quadric::default(); members.len()];
+        // edges by groups, so seams are not mistaken for borders
+        let mut edges: HashMap<(u32, u32), usize> = HashMap::new();
+
+        for (index, triangle) in triangles.iter().enumerate() {
+            let corners = triangle.map(|vertex| group_of[vertex as usize]);
+            let [a, b, c] = corners.map(|group| positions[group as usize]);
+            let cross = (b - a).cross(c - a);
+            let area = cross.mag() as f64 * 0.5;
+            if area > 0.0 {
+                let quadric = Quadric::from_plane(cross.normalized(), a, area);
+                for group in corners {
+                    quadrics[group as usize].add(&quadric);
+                }
+            }
+            for corner in 0..3 {
+                vertex_triangles[triangle[corner] as usize].push(index);
+                let (from, to) = (corners[corner], corners[(corner + 1) % 3]);
+                *edges.entry((from.min(to), from.max(to))).or_default() += 1;
+            }
+        }
+
+        // plane through the border edge perpendicular to its triangle keeps the border in place
+        for triangle in &triangles {
+            let corners = triangle.map(|vertex| group_of[vertex as usize]);
+            let [a, b, c] = corners.map(|group| positions[group as usize]);
+            let normal = (b - a).cross(c - a);
+            if normal.mag() == 0.0 {
+                continue;
+            }
+            for corner in 0..3 {
+                let (from, to) = (corners[corner], corners[(corner + 1) % 3]);
+                if edges[&(from.min(to), from.max(to))] != 1 {
+                    continue;
+                }
+                let edge = positions[to as usize] - positions[from as usize];
+                let border_normal = edge.cross(normal);
+                if border_normal.mag() == 0.0 {
+                    continue;
+                }
+                let quadric = Quadric::from_plane(
+                    border_normal.normalized(),
+                    positions[from as usize],
+                    BOUNDARY_WEIGHT * edge.mag_sq() as f64,
+                );
+                quadrics[from as usize].add(&quadric);
+                quadrics[to as usize].add(&quadric);
+            }
+        }
+
+        let mut simplifier = Self {
+            vertices,
+            attribute_words,
+            live_triangles: triangles.len(),
+            alive: vec![true; triangles.len()],
+            removed: vec![false; members.len()],
+            versions: vec![0; members.len()],
+            heap: BinaryHeap::new(),
+            triangles,
+            vertex_triangles,
+            group_of,
+            members,
+            positions,
+            quadrics,
+        };
+        for group in 0..simplifier.members.len() {
+            simplifier.push_candidates(group as u32);
+        }
+        simplifier
+    }
+
+    fn push_candidate(&mut self, from: u32, to: u32) {
+        if self.removed[from as usize] || from == to {
+            return;
+        }
+        let mut quadric = self.quadrics[from as usize];
+        quadric.add(&self.quadrics[to as usize]);
+        self.heap.push(Candidate {
+            cost: quadric.error(self.positions[to as usize]),
+            from,
+            to,
+            from_version: self.versions[from as usize],
+            to_version: self.versions[to as usize],
+        });
+    }
+
+    fn live_triangles_of(&self, vertex: u32) -> impl Iterator<Item = usize> + '_ {
+        self.vertex_triangles[vertex as usize]
+            .iter()
+            .copied()
+            .filter(|&triangle| self.alive[triangle])
+    }
+
+    // Both directions of every edge around group
+    fn push_candidates(&mut self, group: u32) {
+        let neighbours: Vec<u32> = self.members[group as usize]
+            .iter()
+            .flat_map(|&vertex| self.live_triangles_of(vertex))
+            .flat_map(|triangle| self.triangles[triangle])
+            .map(|vertex| self.group_of[vertex as usize])
+            .filter(|&neighbour| neighbour != group)
+            .collect();
+        for neighbour in neighbours {
+            self.push_candidate(group, neighbour);
+            self.push_candidate(neighbour, group);
+        }
+    }
+
+    fn same_attributes(&self, a: u32, b: u32) -> bool {
+        let (a, b) = (self.vertices[a as usize], self.vertices[b as usize]);
+        self.attribute_words
+            .iter()
+            .all(|&word| a[word].to_bits() == b[word].to_bits())
+    }
+
+    // Vertex of group to which vertex takes place of, None refuses the collapse
+    fn target_vertex(&self, vertex: u32, to: u32) -> Option<u32> {
+        let neighbour = self
+            .live_triangles_of(vertex)
+            .flat_map(|triangle| self.triangles[triangle])
+            .find(|&corner| self.group_of[corner as usize] == to);
+        neighbour.or_else(|| {
+            self.members[to as usize]
+                .iter()
+                .copied()
+                .find(|&target| self.same_attributes(vertex, target))
+        })
+    }
+
+    fn try_collapse(&mut self, candidate: Candidate) {
+        let (from, to) = (candidate.from as usize, candidate.to as usize);
+        if self.removed[from]
+            || self.removed[to]
+            || self.versions[from] != candidate.from_version
+            || self.versions[to] != candidate.to_version
+        {
+            return;
+        }
+
+        let mut moves: Vec<(u32, u32)> = vec![];
+        for &vertex in &self.members[from] {
+            if self.live_triangles_of(vertex).next().is_none() {
+                continue;
+            }
+            match self.target_vertex(vertex, candidate.to) {
+                Some(target) => moves.push((vertex, target)),
+                None => return,
+            }
+        }
+
+        let target = self.positions[to];
+        for &(vertex, _) in &moves {
+            for triangle in self.live_triangles_of(vertex) {
+                let corners = self.triangles[triangle].map(|corner| self.group_of[corner as usize]);
+                if corners.contains(&candidate.to) {
+                    continue;
+                }
+                let [a, b, c] = corners.map(|group| self.positions[group as usize]);
+                let before = (b - a).cross(c - a);
+                let [a, b, c] = corners.map(|group| match group == candidate.from {
+                    true => target,
+                    false => self.positions[group as usize],
+                });
+                let after = (b - a).cross(c - a);
+                if after.mag() == 0.0
+                    || before.mag() == 0.0
+                    || before.normalized().dot(after.normalized()) < MIN_NORMAL_COSINE
+                {
+                    return;
+                }
+            }
+        }
+
+        for (vertex, target) in moves {
+            let triangles: Vec<usize> = self.live_triangles_of(vertex).collect();
+            for triangle in triangles {
+                let corners = &mut self.triangles[triangle];
+                if corners
+                    .iter()
+                    .any(|&corner| self.group_of[corner as usize] == candidate.to)
+                {
+                    self.alive[triangle] = false;
+                    self.live_triangles -= 1;
+                    continue;
+                }
+                for corner in corners.iter_mut() {
+                    if *corner == vertex {
+                        *corner = target;
+                    }
+                }
+                self.vertex_triangles[target as usize].push(triangle);
+            }
+            self.vertex_triangles[vertex as usize].clear();
+        }
+        self.removed[from] = true;
+        let quadric = self.quadrics[from];
+        self.quadrics[to].add(&quadric);
+        self.versions[to] += 1;
+        self.push_candidates(candidate.to);
+    }
+
+    fn indices(&self) -> Vec<u32> {
+        self.triangles
+            .iter()
+            .zip(&self.alive)
+            .filter(|(_, alive)| **alive)
+            .flat_map(|(triangle, _)| *triangle)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::MeshData;
+    use crate::vertex::{AttributeType, Semantic};
+
+    fn layout() -> VertexLayout {
+        VertexLayout::new()
+            .with(Semantic::Position, 0, 3, AttributeType::Float, false)
+            .with(Semantic::TexCoord(0), 2, 2, AttributeType::Float, false)
+    }
+
+    fn vertex(layout: &VertexLayout, vertex_data: &mut Vec<f32>, position: Vec3, uv: [f32; 2]) {
+        let mut vertex = vec![0.0; layout.floats()];
+        layout.write(&mut vertex, Semantic::Position, position.as_slice());
+        layout.write(&mut vertex, Semantic::TexCoord(0), &uv);
+        vertex_data.extend(vertex);
+    }
+
+    fn position(layout: &VertexLayout, vertex_data: &[f32], index: u32) -> Vec3 {
+        let (position, _) = layout.position_normal();
+        let vertex = &vertex_data[index as usize * layout.floats()..];
+        Vec3::new(vertex[position], vertex[position + 1], vertex[position + 2])
+    }
+
+    // Cube with side cells per face pushed onto the unit sphere, one vertex per position
+    fn sphere(side: i32) -> (Vec<f32>, Vec<u32>) {
+        let layout = layout();
+        let mut vertex_data = vec![];
+        let mut indices = vec![];
+        let mut welded: HashMap<[i32; 3], u32> = HashMap::new();
+        let mut index_of = |point: [i32; 3]| {
+            *welded.entry(point).or_insert_with(|| {
+                let position = Vec3::new(point[0] as f32, point[1] as f32, point[2] as f32);
+                vertex(&layout, &mut vertex_data, position.normalized(), [0.0; 2]);
+                (vertex_data.len() / layout.floats() - 1) as u32
+            })
+        };
+        for axis in 0..3 {
+            for face in [-side, side] {
+                for a in (-side..side).step_by(2) {
+                    for b in (-side..side).step_by(2) {
+                        let corner = |a: i32, b: i32| {
+                            let mut point = [0; 3];
+                            point[axis] = face;
+                            point[(axis + 1) % 3] = a;
+                            point[(axis + 2) % 3] = b;
+                            point
+                        };
+                        let quad = [
+                            corner(a, b),
+                            corner(a + 2, b),
+                            corner(a + 2, b + 2),
+                            corner(a, b + 2),
+                        ]
+                        .map(&mut index_of);
+                        // outwards on both faces of the axis
+                        let quad = match face > 0 {
+                            true => quad,
+                            false => [quad[3], quad[2], quad[1], quad[0]],
+                        };
+                        indices.extend([quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+                    }
+                }
+            }
+        }
+        (vertex_data, indices)
+    }
+
+    // Flat cells x cells grid, the left and the right half are separate UV islands (u from 0 and
+    // from 2), so the middle column is a UV seam
+    fn grid_with_seam(cells: u32) -> (Vec<f32>, Vec<u32>) {
+        let layout = layout();
+        let mut vertex_data = vec![];
+        let mut index_of = HashMap::new();
+        for island in 0..2 {
+            let columns = match island {
+                0 => 0..=cells / 2,
+                _ => cells / 2..=cells,
+            };
+            for x in columns {
+                for y in 0..=cells {
+                    let u = x as f32 / cells as f32 + island as f32 * 2.0;
+                    let position = Vec3::new(x as f32, y as f32, 0.0);
+                    vertex(&layout, &mut vertex_data, position, [u, y as f32]);
+                    index_of.insert((island, x, y), index_of.len() as u32);
+                }
+            }
+        }
+        let mut indices = vec![];
+        for x in 0..cells {
+            for y in 0..cells {
+                let island = (x >= cells / 2) as u32;
+                let [a, b, c, d] = [(x, y), (x + 1, y), (x + 1, y + 1), (x, y + 1)]
+                    .map(|(x, y)| index_of[&(island, x, y)]);
+                indices.extend([a, b, c, a, c, d]);
+            }
+        }
+        (vertex_data, indices)
+    }
+
+    fn check_indices(vertex_data: &[f32], indices: &[u32]) {
+        let vertex_count = (vertex_data.len() / layout().floats()) as u32;
+        assert_eq!(indices.len() % 3, 0);
+        for triangle in indices.chunks_exact(3) {
+            assert!(triangle.iter().all(|&index| index < vertex_count));
+            assert!(triangle[0] != triangle[1] && triangle[1] != triangle[2]);
+            assert!(triangle[0] != triangle[2]);
+        }
+    }
+
+    // Signed area of triangles on the z = 0 plane, flipped ones take away
+    fn area(vertex_data: &[f32], indices: &[u32]) -> f32 {
+        let layout = layout();
+        indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                let [a, b, c] = [0, 1, 2].map(|at| position(&layout, vertex_data, triangle[at]));
+                (b - a).cross(c - a).z * 0.5
+            })
+            .sum()
+    }
+
+    #[test]
+    fn closed_mesh_reaches_target_ratio() {
+        let (vertex_data, indices) = sphere(8);
+        let triangle_count = indices.len() / 3;
+        for ratio in [0.5, 0.25, 0.1] {
+            let lod = simplify_indices(&layout(), &vertex_data, &indices, ratio);
+            check_indices(&vertex_data, &lod);
+            // every collapse on a closed mesh takes away two triangles
+            let target = (triangle_count as f32 * ratio).round() as usize;
+            assert!(lod.len() / 3 <= target, "{} > {}", lod.len() / 3, target);
+            assert!(
+                lod.len() / 3 + 2 >= target,
+                "{} < {}",
+                lod.len() / 3,
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn full_ratio_keeps_indices() {
+        let (vertex_data, indices) = sphere(4);
+        assert_eq!(
+            simplify_indices(&layout(), &vertex_data, &indices, 1.0),
+            indices
+        );
+    }
+
+    #[test]
+    fn border_and_uv_seam_are_kept() {
+        let layout = layout();
+        let (vertex_data, indices) = grid_with_seam(8);
+        let lod = simplify_indices(&layout, &vertex_data, &indices, 0.25);
+        check_indices(&vertex_data, &lod);
+        assert!(lod.len() < indices.len());
+
+        let uv = layout.word_offset(Semantic::TexCoord(0)).unwrap();
+        let u = |index: u32| vertex_data[index as usize * layout.floats() + uv];
+        let mut islands = [vec![], vec![]];
+        for triangle in lod.chunks_exact(3) {
+            let island = (u(triangle[0]) >= 2.0) as usize;
+            assert!(
+                triangle
+                    .iter()
+                    .all(|&index| (u(index) >= 2.0) as usize == island)
+            );
+            islands[island].extend_from_slice(triangle);
+        }
+        // the same area on both sides of the seam means neither the border nor the seam moved
+        for island in islands {
+            assert!((area(&vertex_data, &island) - 32.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn flat_shaded_meshes_are_not_reduced() {
+        for path in ["obj/cube.obj", "obj/brick.obj"] {
+            let mesh = MeshData::load(path);
+            let indices = &mesh.indices[mesh.lods[0].clone()];
+            let lod = simplify_indices(&mesh.layout, &mesh.vertex_data, indices, 0.5);
+            assert_eq!(lod, indices, "{}", path);
+        }
+    }
+}
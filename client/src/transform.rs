@@ -0,0 +1,469 @@
+#![allow(unused, dead_code)]
+use crate::mesh::MeshData;
+use crate::vertex::VertexLayout;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Instant;
+use ultraviolet::{Mat3, Mat3x8, Mat4, Mat4x8, Vec3, Vec3x8, Vec4, Vec4x8, f32x8};
+
+/*
+    transform:
+    0. positions (and normals) of 8 vertices at once go through Vec3x8/Mat4x8
+    1. vertices left over from the last 8 are done one by one
+    2. meshes with PARALLEL_VERTICES or more vertices are split into one part per core, the
+       calling thread does one part and workers of the pool the others
+    3. the pool is made on first use and its threads live as long as the program, so transforms
+       every frame don't spawn threads
+*/
+
+const LANES: usize = 8;
+// below this handing parts to the workers takes longer than the transform itself
+const PARALLEL_VERTICES: usize = 16 * 1024;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct WorkerPool {
+    sender: Mutex<Sender<Job>>,
+}
+
+fn cores() -> usize {
+    thread::available_parallelism().map_or(1, |cores| cores.get())
+}
+
+// One worker less than cores (but at least one), the calling thread works too
+fn pool() -> &'static WorkerPool {
+    static POOL: OnceLock<WorkerPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 1..cores().max(2) {
+            let receiver = receiver.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                }
+            });
+        }
+        WorkerPool {
+            sender: Mutex::new(sender),
+        }
+    })
+}
+
+impl WorkerPool {
+    // Runs the first job on the calling thread and the rest on the workers, returns once every
+    // one of them is done, so jobs can borrow from the caller
+    fn scope<'a>(&self, mut jobs: Vec<Box<dyn FnOnce() + Send + 'a>>) {
+        if jobs.is_empty() {
+            return;
+        }
+        let first = jobs.remove(0);
+        let count = jobs.len();
+        let (done_sender, done) = mpsc::channel();
+        {
+            let sender = self.sender.lock().unwrap();
+            for job in jobs {
+                let done_sender = done_sender.clone();
+                let job: Box<dyn FnOnce() + Send + 'a> = Box::new(move || {
+                    let result = panic::catch_unwind(AssertUnwindSafe(job));
+                    done_sender.send(result.is_ok()).unwrap();
+                });
+                // borrows of 'a outlive the job, scope does not return (or unwind) before
+                // every job sent its done message
+                let job: Job = unsafe { std::mem::transmute(job) };
+                sender.send(job).unwrap();
+            }
+        }
+        let first = panic::catch_unwind(AssertUnwindSafe(first));
+        let mut panicked = false;
+        for _ in 0..count {
+            panicked |= !done.recv().unwrap();
+        }
+        if let Err(payload) = first {
+            panic::resume_unwind(payload);
+        }
+        if panicked {
+            panic!("Worker of the transform pool panicked!");
+        }
+    }
+}
+
+// Threads used for vertex_count vertices, every one of them gets at least PARALLEL_VERTICES / 2
+fn thread_count(vertex_count: usize) -> usize {
+    if vertex_count < PARALLEL_VERTICES {
+        return 1;
+    }
+    cores().min(vertex_count / (PARALLEL_VERTICES / 2)).max(1)
+}
+
+// Runs work on parts of vertex_data split on vertex boundaries, on more threads for big meshes
+fn split_vertices(floats: usize, vertex_data: &mut [f32], work: impl Fn(usize, &mut [f32]) + Sync) {
+    let vertex_count = vertex_data.len() / floats;
+    let threads = thread_count(vertex_count);
+    if threads == 1 {
+        work(0, vertex_data);
+        return;
+    }
+    let part = vertex_count.div_ceil(threads) * floats;
+    let work = &work;
+    pool().scope(
+        vertex_data
+            .chunks_mut(part)
+            .enumerate()
+            .map(|(at, vertices)| {
+                Box::new(move || work(at * part, vertices)) as Box<dyn FnOnce() + Send + '_>
+            })
+            .collect(),
+    );
+}
+
+fn gather(vertices: &[f32], floats: usize, at: usize) -> Vec3x8 {
+    let lane = |component: usize| {
+        f32x8::from(std::array::from_fn::<f32, LANES, _>(|vertex| {
+            vertices[vertex * floats + at + component]
+        }))
+    };
+    Vec3x8::new(lane(0), lane(1), lane(2))
+}
+
+fn scatter(vertices: &mut [f32], floats: usize, at: usize, values: Vec3x8) {
+    let (x, y, z) = (
+        values.x.to_array(),
+        values.y.to_array(),
+        values.z.to_array(),
+    );
+    for (vertex, out) in vertices.chunks_exact_mut(floats).enumerate() {
+        out[at] = x[vertex];
+        out[at + 1] = y[vertex];
+        out[at + 2] = z[vertex];
+    }
+}
+
+// Moves positions of every vertex in vertex_data by delta
+pub fn translate_positions(layout: &VertexLayout, vertex_data: &mut [f32], delta: Vec3) {
+    let (position, _) = layout.position_normal();
+    let floats = layout.floats();
+    let delta_x8 = Vec3x8::splat(delta);
+    split_vertices(floats, vertex_data, |_, vertex_data| {
+        let mut wide = vertex_data.chunks_exact_mut(floats * LANES);
+        for vertices in &mut wide {
+            let moved = gather(vertices, floats, position) + delta_x8;
+            scatter(vertices, floats, position, moved);
+        }
+        for vertex in wide.into_remainder().chunks_exact_mut(floats) {
+            vertex[position] += delta.x;
+            vertex[position + 1] += delta.y;
+            vertex[position + 2] += delta.z;
+        }
+    });
+}
+
+// Normals go through inverse transpose, so they stay perpendicular under non-uniform scale
+fn normal_matrix(model: Mat4) -> Mat3 {
+    model.inversed().transposed().truncate()
+}
+
+// Zero normals (meshes without vn) stay zero instead of turning into NaN
+fn normalized(norm: Vec3) -> Vec3 {
+    if norm.mag_sq() > 0.0 {
+        norm.normalized()
+    } else {
+        norm
+    }
+}
+
+// Same as normalized on 8 lanes, zero lanes are the only ones which come out of normalized()
+// as NaN
+fn normalized_x8(norm: Vec3x8) -> Vec3x8 {
+    let normalized = norm.normalized();
+    Vec3x8::blend(normalized.x.is_nan(), Vec3x8::zero(), normalized)
+}
+
+// Writes local vertices transformed by model into out, only position, normal and tangent change
+// Tangents go through model itself (they lie along the surface), their w stays
+pub fn transform_vertices(
+    layout: &VertexLayout,
+    local_vertex_data: &[f32],
+    out: &mut [f32],
+    model: Mat4,
+) {
+    let floats = layout.floats();
+    split_vertices(floats, out, |start, out| {
+        transform_vertices_wide(
+            layout,
+            &local_vertex_data[start..start + out.len()],
+            out,
+            model,
+        );
+    });
+}
+
+// Same as transform_vertices on the calling thread only
+pub fn transform_vertices_wide(
+    layout: &VertexLayout,
+    local_vertex_data: &[f32],
+    out: &mut [f32],
+    model: Mat4,
+) {
+    let (position, normal) = layout.position_normal();
+    let tangent = layout.tangent();
+    let floats = layout.floats();
+    let normal_matrix = normal_matrix(model);
+    let linear = model.truncate();
+    let model_x8 = Mat4x8::new(
+        Vec4x8::splat(model.cols[0]),
+        Vec4x8::splat(model.cols[1]),
+        Vec4x8::splat(model.cols[2]),
+        Vec4x8::splat(model.cols[3]),
+    );
+    let normal_matrix_x8 = Mat3x8::new(
+        Vec3x8::splat(normal_matrix.cols[0]),
+        Vec3x8::splat(normal_matrix.cols[1]),
+        Vec3x8::splat(normal_matrix.cols[2]),
+    );
+    let linear_x8 = Mat3x8::new(
+        Vec3x8::splat(linear.cols[0]),
+        Vec3x8::splat(linear.cols[1]),
+        Vec3x8::splat(linear.cols[2]),
+    );
+    out.copy_from_slice(local_vertex_data);
+
+    let mut wide = out.chunks_exact_mut(floats * LANES);
+    for vertices in &mut wide {
+        let pos = model_x8.transform_point3(gather(vertices, floats, position));
+        scatter(vertices, floats, position, pos);
+        if let Some(normal) = normal {
+            let norm = normalized_x8(normal_matrix_x8 * gather(vertices, floats, normal));
+            scatter(vertices, floats, normal, norm);
+        }
+        if let Some(tangent) = tangent {
+            let tan = normalized_x8(linear_x8 * gather(vertices, floats, tangent));
+            scatter(vertices, floats, tangent, tan);
+        }
+    }
+    for vertex in wide.into_remainder().chunks_exact_mut(floats) {
+        transform_vertex(vertex, position, normal, tangent, model, normal_matrix);
+    }
+}
+
+// One Vec4 at a time, kept for the benchmark
+pub fn transform_vertices_scalar(
+    layout: &VertexLayout,
+    local_vertex_data: &[f32],
+    out: &mut [f32],
+    model: Mat4,
+) {
+    let (position, normal) = layout.position_normal();
+    let tangent = layout.tangent();
+    let normal_matrix = normal_matrix(model);
+    out.copy_from_slice(local_vertex_data);
+    for vertex in out.chunks_exact_mut(layout.floats()) {
+        transform_vertex(vertex, position, normal, tangent, model, normal_matrix);
+    }
+}
+
+fn transform_vertex(
+    vertex: &mut [f32],
+    position: usize,
+    normal: Option<usize>,
+    tangent: Option<usize>,
+    model: Mat4,
+    normal_matrix: Mat3,
+) {
+    let pos = model
+        * Vec4::new(
+            vertex[position],
+            vertex[position + 1],
+            vertex[position + 2],
+            1.0,
+        );
+    vertex[position] = pos.x;
+    vertex[position + 1] = pos.y;
+    vertex[position + 2] = pos.z;
+
+    if let Some(normal) = normal {
+        let norm = normalized(
+            normal_matrix * Vec3::new(vertex[normal], vertex[normal + 1], vertex[normal + 2]),
+        );
+        vertex[normal] = norm.x;
+        vertex[normal + 1] = norm.y;
+        vertex[normal + 2] = norm.z;
+    }
+
+    if let Some(tangent) = tangent {
+        let tan = normalized(
+            model.truncate() * Vec3::new(vertex[tangent], vertex[tangent + 1], vertex[tangent + 2]),
+        );
+        vertex[tangent] = tan.x;
+        vertex[tangent + 1] = tan.y;
+        vertex[tangent + 2] = tan.z;
+    }
+}
+
+// Times scalar, wide and pooled transforms of the mesh (repeated copies times, so the pooled one
+// has something to split) and prints them, run with --bench-transform
+pub fn benchmark(obj_path: &str, copies: usize, iterations: usize) {
+    let mesh = MeshData::load(obj_path);
+    let local = mesh.vertex_data.repeat(copies);
+    let mut out = vec![0.0; local.len()];
+    let model = Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0))
+        * Mat4::from_rotation_y(0.5)
+        * Mat4::from_nonuniform_scale(Vec3::new(1.0, 2.0, 0.5));
+    println!(
+        "{}: {} vertices x {} copies, {} iterations, {} threads",
+        obj_path,
+        mesh.vertex_data.len() / mesh.layout.floats(),
+        copies,
+        iterations,
+        thread_count(local.len() / mesh.layout.floats()),
+    );
+
+    // the pool is made outside of the timing, like it is by the first frame
+    pool();
+    let mut run = |name: &str, transform: fn(&VertexLayout, &[f32], &mut [f32], Mat4)| {
+        let start = Instant::now();
+        for _ in 0..iterations {
+            transform(&mesh.layout, &local, &mut out, model);
+        }
+        let elapsed = start.elapsed() / iterations as u32;
+        println!("{:>8}: {:?}", name, elapsed);
+        elapsed
+    };
+    let scalar = run("scalar", transform_vertices_scalar);
+    let wide = run("wide", transform_vertices_wide);
+    let pooled = run("pool", transform_vertices);
+    println!(
+        "speedup: wide {:.2}x, wide + pool {:.2}x",
+        scalar.as_secs_f64() / wide.as_secs_f64(),
+        scalar.as_secs_f64() / pooled.as_secs_f64(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vertex::{AttributeType, Semantic};
+
+    // 9 vertices, so both the 8 wide lanes and the one by one remainder run
+    fn vertices(layout: &VertexLayout, normal: impl Fn(usize) -> Vec3) -> Vec<f32> {
+        let (position, normal_at) = layout.position_normal();
+        let normal_at = normal_at.unwrap();
+        let mut vertex_data = vec![0.0; layout.floats() * 9];
+        for (index, vertex) in vertex_data.chunks_exact_mut(layout.floats()).enumerate() {
+            vertex[position] = index as f32;
+            let normal = normal(index);
+            vertex[normal_at..normal_at + 3].copy_from_slice(normal.as_slice());
+        }
+        vertex_data
+    }
+
+    fn model() -> Mat4 {
+        Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0))
+            * Mat4::from_rotation_y(0.5)
+            * Mat4::from_nonuniform_scale(Vec3::new(1.0, 2.0, 0.5))
+    }
+
+    #[test]
+    fn zero_normals_stay_zero() {
+        let layout = VertexLayout::standard();
+        let (_, normal) = layout.position_normal();
+        let normal = normal.unwrap();
+        let local = vertices(&layout, |_| Vec3::zero());
+        let mut wide = vec![0.0; local.len()];
+        let mut scalar = vec![0.0; local.len()];
+        transform_vertices_wide(&layout, &local, &mut wide, model());
+        transform_vertices_scalar(&layout, &local, &mut scalar, model());
+        for vertex in wide.chunks_exact(layout.floats()) {
+            assert_eq!(&vertex[normal..normal + 3], &[0.0; 3]);
+        }
+        assert_eq!(wide, scalar);
+    }
+
+    #[test]
+    fn wide_matches_scalar() {
+        let layout = VertexLayout::standard();
+        // every other normal is missing
+        let local = vertices(&layout, |index| {
+            if index % 2 == 0 {
+                Vec3::zero()
+            } else {
+                Vec3::new(index as f32, 1.0, -1.0)
+            }
+        });
+        let mut wide = vec![0.0; local.len()];
+        let mut scalar = vec![0.0; local.len()];
+        transform_vertices_wide(&layout, &local, &mut wide, model());
+        transform_vertices_scalar(&layout, &local, &mut scalar, model());
+        for (wide, scalar) in wide.iter().zip(&scalar) {
+            assert!((wide - scalar).abs() < 1e-5, "{} != {}", wide, scalar);
+        }
+    }
+
+    // more jobs than workers wait for a free one, every job is done before scope returns
+    #[test]
+    fn pool_runs_every_job_on_borrowed_data() {
+        let mut parts = vec![0; 16];
+        for _ in 0..2 {
+            pool().scope(
+                parts
+                    .chunks_mut(1)
+                    .enumerate()
+                    .map(|(at, part)| {
+                        Box::new(move || part[0] += at) as Box<dyn FnOnce() + Send + '_>
+                    })
+                    .collect(),
+            );
+        }
+        assert_eq!(parts, (0..16).map(|at| at * 2).collect::<Vec<_>>());
+    }
+
+    // enough vertices to be split between the pool workers, run twice so the same workers are
+    // given work again
+    #[test]
+    fn pool_matches_wide() {
+        let layout = VertexLayout::standard();
+        let local = vertices(&layout, |index| Vec3::new(index as f32, 1.0, -1.0))
+            .repeat(PARALLEL_VERTICES * 2 / 9 + 1);
+        let mut wide = vec![0.0; local.len()];
+        transform_vertices_wide(&layout, &local, &mut wide, model());
+        for _ in 0..2 {
+            let mut pooled = vec![0.0; local.len()];
+            transform_vertices(&layout, &local, &mut pooled, model());
+            assert_eq!(pooled, wide);
+        }
+    }
+
+    #[test]
+    fn tangents_follow_model_and_keep_handedness() {
+        let layout =
+            VertexLayout::standard().with(Semantic::Tangent, 5, 4, AttributeType::Float, false);
+        let tangent = layout.tangent().unwrap();
+        let mut local = vertices(&layout, |_| Vec3::unit_y());
+        for vertex in local.chunks_exact_mut(layout.floats()) {
+            vertex[tangent..tangent + 4].copy_from_slice(&[2.0, 0.0, 0.0, -1.0]);
+        }
+        let model = Mat4::from_rotation_y(std::f32::consts::FRAC_PI_2);
+        let mut wide = vec![0.0; local.len()];
+        let mut scalar = vec![0.0; local.len()];
+        transform_vertices_wide(&layout, &local, &mut wide, model);
+        transform_vertices_scalar(&layout, &local, &mut scalar, model);
+        for out in [&wide, &scalar] {
+            for vertex in out.chunks_exact(layout.floats()) {
+                let expected = [0.0, 0.0, -1.0, -1.0];
+                for (got, expected) in vertex[tangent..tangent + 4].iter().zip(expected) {
+                    assert!(
+                        (got - expected).abs() < 1e-5,
+                        "{:?}",
+                        &vertex[tangent..tangent + 4]
+                    );
+                }
+            }
+        }
+    }
+}
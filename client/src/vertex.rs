@@ -0,0 +1,371 @@
+#![allow(unused, dead_code)]
+use gl33::*;
+
+/*
+    VertexLayout:
+    0. vertex data is kept as Vec<f32>, every attribute takes whole 4 byte words
+    1. non float attributes are packed into those words (4 x u8 colour is one word)
+    2. position and normal have to be 3 x Float, tangent 3 or 4 x Float (w is the handedness),
+       batches transform them on the CPU
+    3. colour and object index can be left out, the shader reads white and -1 for them then
+*/
+
+// Locations vert.glsl reads colour and object index from
+const COLOR_LOCATION: u32 = 3;
+const OBJECT_INDEX_LOCATION: u32 = 4;
+
+// Every float input of vert.glsl, attributes of these semantics have to be at these locations
+const SHADER_INPUTS: [(Semantic, u32); 5] = [
+    (Semantic::Position, 0),
+    (Semantic::Normal, 1),
+    (Semantic::TexCoord(0), 2),
+    (Semantic::Color, COLOR_LOCATION),
+    (Semantic::ObjectIndex, OBJECT_INDEX_LOCATION),
+];
+
+// What the attribute means, loaders and batches find attributes by it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Semantic {
+    Position,
+    Normal,
+    TexCoord(u8),
+    Color,
+    // index of the model matrix in DynamicBatch (TransformMode::Gpu), -1 without one
+    ObjectIndex,
+    Tangent,
+    BoneIndices,
+    BoneWeights,
+    Custom(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeType {
+    Float,
+    Byte,
+    UnsignedByte,
+    Short,
+    UnsignedShort,
+    Int,
+    UnsignedInt,
+}
+
+impl AttributeType {
+    pub fn size(&self) -> usize {
+        match self {
+            AttributeType::Float | AttributeType::Int | AttributeType::UnsignedInt => 4,
+            AttributeType::Short | AttributeType::UnsignedShort => 2,
+            AttributeType::Byte | AttributeType::UnsignedByte => 1,
+        }
+    }
+
+    pub fn gl_type(&self) -> GLenum {
+        match self {
+            AttributeType::Float => GL_FLOAT,
+            AttributeType::Byte => GL_BYTE,
+            AttributeType::UnsignedByte => GL_UNSIGNED_BYTE,
+            AttributeType::Short => GL_SHORT,
+            AttributeType::UnsignedShort => GL_UNSIGNED_SHORT,
+            AttributeType::Int => GL_INT,
+            AttributeType::UnsignedInt => GL_UNSIGNED_INT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VertexAttribute {
+    pub semantic: Semantic,
+    pub location: u32,
+    pub components: usize,
+    pub ty: AttributeType,
+    // integer types only, read as 0..1 (or -1..1) floats in the shader
+    pub normalized: bool,
+    // offset from the start of the vertex in bytes
+    pub offset: usize,
+}
+
+impl VertexAttribute {
+    // integer attribute which is not normalized goes to ivec/uvec in the shader
+    pub fn is_integer(&self) -> bool {
+        self.ty != AttributeType::Float && !self.normalized
+    }
+
+    pub fn words(&self) -> usize {
+        (self.components * self.ty.size()).div_ceil(4)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VertexLayout {
+    attributes: Vec<VertexAttribute>,
+    stride: usize,
+}
+
+impl VertexLayout {
+    pub fn new() -> Self {
+        Self {
+            attributes: vec![],
+            stride: 0,
+        }
+    }
+
+    // position, normal, uv, colour, object index, what vert.glsl expects
+    pub fn standard() -> Self {
+        Self::new()
+            .with(Semantic::Position, 0, 3, AttributeType::Float, false)
+            .with(Semantic::Normal, 1, 3, AttributeType::Float, false)
+            .with(Semantic::TexCoord(0), 2, 2, AttributeType::Float, false)
+            .with(Semantic::Color, 3, 3, AttributeType::Float, false)
+            .with(Semantic::ObjectIndex, 4, 1, AttributeType::Float, false)
+    }
+
+    // Appends attribute after the last one
+    pub fn with(
+        mut self,
+        semantic: Semantic,
+        location: u32,
+        components: usize,
+        ty: AttributeType,
+        normalized: bool,
+    ) -> Self {
+        if self.attribute(semantic).is_some() {
+            panic!("Semantic {:?} already in VertexLayout!", semantic);
+        }
+        let attribute = VertexAttribute {
+            semantic,
+            location,
+            components,
+            ty,
+            normalized,
+            offset: self.stride,
+        };
+        self.stride += attribute.words() * size_of::<f32>();
+        self.attributes.push(attribute);
+        self
+    }
+
+    pub fn attributes(&self) -> &[VertexAttribute] {
+        &self.attributes
+    }
+
+    pub fn attribute(&self, semantic: Semantic) -> Option<&VertexAttribute> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.semantic == semantic)
+    }
+
+    // size of one vertex in bytes
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    // size of one vertex in f32 words
+    pub fn floats(&self) -> usize {
+        self.stride / size_of::<f32>()
+    }
+
+    // index of the first word of the attribute inside one vertex
+    pub fn word_offset(&self, semantic: Semantic) -> Option<usize> {
+        self.attribute(semantic)
+            .map(|attribute| attribute.offset / size_of::<f32>())
+    }
+
+    // Word offsets of 3 float position and normal, batches transform them (and the tangent)
+    pub fn position_normal(&self) -> (usize, Option<usize>) {
+        let position = match self.attribute(Semantic::Position) {
+            Some(attribute)
+                if attribute.ty == AttributeType::Float && attribute.components == 3 =>
+            {
+                attribute.offset / size_of::<f32>()
+            }
+            _ => panic!("VertexLayout needs 3 x Float position!"),
+        };
+        let normal = match self.attribute(Semantic::Normal) {
+            Some(attribute)
+                if attribute.ty == AttributeType::Float && attribute.components == 3 =>
+            {
+                Some(attribute.offset / size_of::<f32>())
+            }
+            Some(_) => panic!("VertexLayout normal has to be 3 x Float!"),
+            None => None,
+        };
+        (position, normal)
+    }
+
+    // Word offset of the tangent, only its xyz are transformed, w stays
+    pub fn tangent(&self) -> Option<usize> {
+        match self.attribute(Semantic::Tangent) {
+            Some(attribute)
+                if attribute.ty == AttributeType::Float
+                    && (attribute.components == 3 || attribute.components == 4) =>
+            {
+                Some(attribute.offset / size_of::<f32>())
+            }
+            Some(_) => panic!("VertexLayout tangent has to be 3 or 4 x Float!"),
+            None => None,
+        }
+    }
+
+    // Errors when a semantic the shader reads is at another location, another semantic is at its
+    // location or it is an integer attribute (the shader reads only floats), GL would draw such
+    // layouts without any error
+    pub fn check_shader_inputs(&self) -> Result<(), String> {
+        for attribute in &self.attributes {
+            for (semantic, location) in SHADER_INPUTS {
+                if (attribute.semantic == semantic) != (attribute.location == location) {
+                    return Err(format!(
+                        "{:?} is at location {}, vert.glsl reads {:?} from location {}",
+                        attribute.semantic, attribute.location, semantic, location
+                    ));
+                }
+                if attribute.location == location && attribute.is_integer() {
+                    return Err(format!(
+                        "{:?} is an integer attribute, vert.glsl reads it as float",
+                        semantic
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Sets attribute pointers for the buffer bound to GL_ARRAY_BUFFER (and the bound VAO), missing
+    // colour and object index get constant values
+    pub fn apply(&self, gl: &GlFns) {
+        for attribute in &self.attributes {
+            unsafe {
+                if attribute.is_integer() {
+                    gl.VertexAttribIPointer(
+                        attribute.location,
+                        attribute.components.try_into().unwrap(),
+                        attribute.ty.gl_type(),
+                        self.stride.try_into().unwrap(),
+                        attribute.offset as *const _,
+                    );
+                } else {
+                    gl.VertexAttribPointer(
+                        attribute.location,
+                        attribute.components.try_into().unwrap(),
+                        attribute.ty.gl_type(),
+                        attribute.normalized as u8,
+                        self.stride.try_into().unwrap(),
+                        attribute.offset as *const _,
+                    );
+                }
+                gl.EnableVertexAttribArray(attribute.location);
+            }
+        }
+
+        // locations without an array read the current attribute value (context state, not kept
+        // in the VAO), by default black colour and object index 0 (a zero matrix, nothing drawn)
+        let has_location = |location: u32| {
+            self.attributes
+                .iter()
+                .any(|attribute| attribute.location == location)
+        };
+        unsafe {
+            if !has_location(COLOR_LOCATION) {
+                gl.VertexAttrib3f(COLOR_LOCATION, 1.0, 1.0, 1.0);
+            }
+            if !has_location(OBJECT_INDEX_LOCATION) {
+                gl.VertexAttrib1f(OBJECT_INDEX_LOCATION, -1.0);
+            }
+        }
+    }
+
+    // Writes values of the semantic into one vertex (slice of layout.floats() words), missing
+    // semantic is skipped and missing values are zero
+    pub fn write(&self, vertex: &mut [f32], semantic: Semantic, values: &[f32]) {
+        let attribute = match self.attribute(semantic) {
+            Some(attribute) => attribute,
+            None => return,
+        };
+        let start = attribute.offset / size_of::<f32>();
+        let words = &mut vertex[start..start + attribute.words()];
+        let value = |component: usize| values.get(component).copied().unwrap_or(0.0);
+
+        if attribute.ty == AttributeType::Float {
+            for (component, word) in words.iter_mut().enumerate() {
+                *word = value(component);
+            }
+            return;
+        }
+
+        let mut bytes = vec![0u8; size_of_val(words)];
+        for component in 0..attribute.components {
+            let v = value(component);
+            let at = component * attribute.ty.size();
+            match (attribute.ty, attribute.normalized) {
+                (AttributeType::Byte, true) => {
+                    bytes[at] = ((v.clamp(-1.0, 1.0) * 127.0).round() as i8) as u8
+                }
+                (AttributeType::Byte, false) => bytes[at] = (v as i8) as u8,
+                (AttributeType::UnsignedByte, true) => {
+                    bytes[at] = (v.clamp(0.0, 1.0) * 255.0).round() as u8
+                }
+                (AttributeType::UnsignedByte, false) => bytes[at] = v as u8,
+                (AttributeType::Short, true) => bytes[at..at + 2].copy_from_slice(
+                    &((v.clamp(-1.0, 1.0) * 32767.0).round() as i16).to_ne_bytes(),
+                ),
+                (AttributeType::Short, false) => {
+                    bytes[at..at + 2].copy_from_slice(&(v as i16).to_ne_bytes())
+                }
+                (AttributeType::UnsignedShort, true) => bytes[at..at + 2]
+                    .copy_from_slice(&((v.clamp(0.0, 1.0) * 65535.0).round() as u16).to_ne_bytes()),
+                (AttributeType::UnsignedShort, false) => {
+                    bytes[at..at + 2].copy_from_slice(&(v as u16).to_ne_bytes())
+                }
+                (AttributeType::Int, _) => {
+                    bytes[at..at + 4].copy_from_slice(&(v as i32).to_ne_bytes())
+                }
+                (AttributeType::UnsignedInt, _) => {
+                    bytes[at..at + 4].copy_from_slice(&(v as u32).to_ne_bytes())
+                }
+                (AttributeType::Float, _) => unreachable!(),
+            }
+        }
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks(size_of::<f32>())) {
+            *word = f32::from_ne_bytes(chunk.try_into().unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shader_inputs_of_standard_layout() {
+        assert!(VertexLayout::standard().check_shader_inputs().is_ok());
+    }
+
+    #[test]
+    fn shader_inputs_allow_missing_and_extra_attributes() {
+        // no object index, packed colour, tangent after the shader inputs
+        let layout = VertexLayout::new()
+            .with(Semantic::Position, 0, 3, AttributeType::Float, false)
+            .with(Semantic::Normal, 1, 3, AttributeType::Float, false)
+            .with(Semantic::TexCoord(0), 2, 2, AttributeType::Float, false)
+            .with(Semantic::Color, 3, 4, AttributeType::UnsignedByte, true)
+            .with(Semantic::Tangent, 5, 4, AttributeType::Float, false);
+        assert!(layout.check_shader_inputs().is_ok());
+        let layout =
+            VertexLayout::new().with(Semantic::Position, 0, 3, AttributeType::Float, false);
+        assert!(layout.check_shader_inputs().is_ok());
+    }
+
+    #[test]
+    fn shader_inputs_refuse_moved_or_replaced_locations() {
+        let moved = VertexLayout::new()
+            .with(Semantic::Position, 0, 3, AttributeType::Float, false)
+            .with(Semantic::Color, 5, 3, AttributeType::Float, false);
+        assert!(moved.check_shader_inputs().is_err());
+        let replaced = VertexLayout::new()
+            .with(Semantic::Position, 0, 3, AttributeType::Float, false)
+            .with(Semantic::Custom(0), 4, 1, AttributeType::Float, false);
+        assert!(replaced.check_shader_inputs().is_err());
+        let integer = VertexLayout::new()
+            .with(Semantic::Position, 0, 3, AttributeType::Float, false)
+            .with(Semantic::Color, 3, 4, AttributeType::UnsignedByte, false);
+        assert!(integer.check_shader_inputs().is_err());
+    }
+}